@@ -0,0 +1,258 @@
+//! The admin API which lists the connections and can kill them.
+//!
+//! Each connection is registered with its UUID which is the same on both sides,
+//! so a connection can be looked up and killed on either of them.
+//! The API is JSON over HTTP and is served on its own address.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use axum::extract::Path;
+use axum::http::StatusCode;
+use axum::routing::get;
+use axum::{Json, Router};
+use log::info;
+use parking_lot::Mutex;
+use serde::Serialize;
+use tokio::net::TcpListener;
+use tokio::sync::Notify;
+use uuid::Uuid;
+
+use crate::metrics;
+use crate::ratelimit::{self, Limiter, Limits};
+use crate::request::Protocol;
+
+/// Every connection which is open right now
+static REGISTRY: OnceLock<Mutex<HashMap<Uuid, Arc<Connection>>>> = OnceLock::new();
+/// Is the control websocket established right now?
+static CONTROLLER_CONNECTED: AtomicBool = AtomicBool::new(false);
+
+/// A connection which is registered in the admin API
+pub(crate) struct Connection {
+    pub id: Uuid,
+    service: String,
+    protocol: Protocol,
+    /// The peer which opened the connection on the local server
+    pub client_address: Option<SocketAddr>,
+    /// What the connection is going to. Only known after the client has asked for it.
+    target: Mutex<Option<String>>,
+    started_at: SystemTime,
+    joined: AtomicBool,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    kill: Notify,
+    /// The bandwidth limits which the socket should wait for
+    pub limiter: Limiter,
+}
+
+impl Connection {
+    /// Marks the connection as joined, which means the target is dialed and the data can flow
+    pub fn set_joined(&self) {
+        self.joined.store(true, Ordering::Relaxed);
+    }
+
+    pub fn set_target(&self, target: String) {
+        *self.target.lock() = Some(target);
+    }
+
+    /// Counts the bytes which are read from the socket of the connection
+    pub fn add_bytes_in(&self, n: usize) {
+        self.bytes_in.fetch_add(n as u64, Ordering::Relaxed);
+        metrics::BYTES_IN.add(n as u64);
+    }
+
+    /// Counts the bytes which are written in the socket of the connection
+    pub fn add_bytes_out(&self, n: usize) {
+        self.bytes_out.fetch_add(n as u64, Ordering::Relaxed);
+        metrics::BYTES_OUT.add(n as u64);
+    }
+
+    /// Closes the connection right away
+    pub fn kill(&self) {
+        // The permit is stored, so the connection is killed even if it's not waiting right now
+        self.kill.notify_one();
+    }
+
+    /// Runs the future until it's done or the connection is killed.
+    /// Returns None if the connection is killed.
+    pub async fn unless_killed<F: Future>(&self, future: F) -> Option<F::Output> {
+        tokio::select! {
+            output = future => Some(output),
+            _ = self.kill.notified() => {
+                info!("Connection {} is killed", self.id);
+                None
+            }
+        }
+    }
+}
+
+/// Keeps a connection registered until it's dropped
+pub(crate) struct Registration(Arc<Connection>);
+
+impl Deref for Registration {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        &self.0
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        registry().lock().remove(&self.0.id);
+    }
+}
+
+/// Registers a new connection. It's listed until the returned registration is dropped.
+pub(crate) fn register(
+    id: Uuid,
+    service: String,
+    protocol: Protocol,
+    client_address: Option<SocketAddr>,
+) -> Registration {
+    let limiter = Limiter::new(&service);
+    let connection = Arc::new(Connection {
+        id,
+        service,
+        protocol,
+        client_address,
+        target: Mutex::new(None),
+        started_at: SystemTime::now(),
+        joined: AtomicBool::new(false),
+        bytes_in: AtomicU64::new(0),
+        bytes_out: AtomicU64::new(0),
+        kill: Notify::new(),
+        limiter,
+    });
+    registry().lock().insert(id, connection.clone());
+    Registration(connection)
+}
+
+fn registry() -> &'static Mutex<HashMap<Uuid, Arc<Connection>>> {
+    REGISTRY.get_or_init(Mutex::default)
+}
+
+/// Looks up a registered connection
+pub(crate) fn lookup(id: Uuid) -> Option<Arc<Connection>> {
+    registry().lock().get(&id).cloned()
+}
+
+/// Reports whether the control websocket is established or not
+pub(crate) fn set_controller_connected(connected: bool) {
+    CONTROLLER_CONNECTED.store(connected, Ordering::Relaxed);
+}
+
+/// How a connection is shown in the API
+#[derive(Serialize)]
+struct ConnectionInfo {
+    id: Uuid,
+    service: String,
+    protocol: Protocol,
+    client_address: Option<SocketAddr>,
+    target: Option<String>,
+    /// Unix timestamp in seconds
+    started_at: u64,
+    state: &'static str,
+    bytes_in: u64,
+    bytes_out: u64,
+}
+
+impl From<&Connection> for ConnectionInfo {
+    fn from(connection: &Connection) -> Self {
+        ConnectionInfo {
+            id: connection.id,
+            service: connection.service.clone(),
+            protocol: connection.protocol,
+            client_address: connection.client_address,
+            target: connection.target.lock().clone(),
+            started_at: connection
+                .started_at
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            state: if connection.joined.load(Ordering::Relaxed) {
+                "joined"
+            } else {
+                "pending"
+            },
+            bytes_in: connection.bytes_in.load(Ordering::Relaxed),
+            bytes_out: connection.bytes_out.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ControllerInfo {
+    connected: bool,
+    /// How many times the control websocket was established
+    connections: u64,
+}
+
+async fn list_connections() -> Json<Vec<ConnectionInfo>> {
+    let mut connections: Vec<ConnectionInfo> = registry()
+        .lock()
+        .values()
+        .map(|connection| ConnectionInfo::from(connection.as_ref()))
+        .collect();
+    connections.sort_by_key(|connection| connection.started_at);
+    Json(connections)
+}
+
+async fn get_connection(Path(id): Path<Uuid>) -> Result<Json<ConnectionInfo>, StatusCode> {
+    let connection = lookup(id).ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(ConnectionInfo::from(connection.as_ref())))
+}
+
+async fn kill_connection(Path(id): Path<Uuid>) -> StatusCode {
+    match lookup(id) {
+        Some(connection) => {
+            info!("Killing connection {id} from the admin API");
+            connection.kill();
+            StatusCode::NO_CONTENT
+        }
+        None => StatusCode::NOT_FOUND,
+    }
+}
+
+async fn get_rate_limits() -> Json<Limits> {
+    Json(ratelimit::limits())
+}
+
+/// Changes the rate limits which are given. The others are kept as is.
+async fn set_rate_limits(Json(limits): Json<Limits>) -> Json<Limits> {
+    info!("Changing the rate limits from the admin API");
+    ratelimit::set_limits(limits);
+    Json(ratelimit::limits())
+}
+
+async fn controller_status() -> Json<ControllerInfo> {
+    Json(ControllerInfo {
+        connected: CONTROLLER_CONNECTED.load(Ordering::Relaxed),
+        connections: metrics::CONTROLLER_CONNECTIONS.get(),
+    })
+}
+
+/// Binds the socket of the admin API, so a bad address stops the program before anything else
+pub(crate) async fn bind(address: &str) -> Result<TcpListener, String> {
+    TcpListener::bind(address)
+        .await
+        .map_err(|err| format!("Cannot bind the admin socket {address}: {err}"))
+}
+
+/// Serves the admin API on the given socket
+pub(crate) async fn serve(listener: TcpListener) {
+    if let Ok(address) = listener.local_addr() {
+        info!("Serving the admin API on {address}");
+    }
+    let app = Router::new()
+        .route("/connections", get(list_connections))
+        .route("/connections/:id", get(get_connection).delete(kill_connection))
+        .route("/controller", get(controller_status))
+        .route("/rate_limits", get(get_rate_limits).put(set_rate_limits));
+    axum::serve(listener, app).await.unwrap()
+}
@@ -1,9 +1,21 @@
+use std::path::PathBuf;
+
 use clap::{Parser, Subcommand};
+use serde::{Deserialize, Serialize};
+use tokio_tungstenite::tungstenite::http::{HeaderName, HeaderValue};
+
+use crate::compression::Algorithm;
+use crate::local::access::IpRange;
+use crate::proxy_protocol::Version;
+use crate::remote::allowlist::DestinationRule;
+use crate::request::DEFAULT_SERVICE;
 
 #[derive(Parser, Debug)]
 #[command(version, about)]
 #[command(about = "A websocket based reverse proxy", long_about = None)]
 pub struct Args {
+    #[arg(long, global = true, env = "RWP_CONFIG", help = "Path of a TOML config file. Command line flags and environment variables override it")]
+    pub config: Option<PathBuf>,
     #[command(subcommand)]
     pub command: Commands,
 }
@@ -11,17 +23,343 @@ pub struct Args {
 #[derive(Debug, Subcommand)]
 pub enum Commands {
     #[command(about = "Run as the server that cloudflare connects to", long_about = None)]
-    Local {
-        #[arg(short = 'l', long, help = "On what address we should listen and accept TCP connections?")]
-        tcp_listen_address: String,
-        #[arg(short = 'c', long, help = "On what address we should listen and accept the connections from Cloudflare?")]
-        cloudflare_listen_address: String,
-    },
+    Local(LocalArgs),
     #[command(about = "Run as the program that connects to cloudflare", long_about = None)]
-    Server {
-        #[arg(short = 'c', long, help = "What is the address of cloudflare that we should send the websockets to?")]
-        cloudflare_server_address: String,
-        #[arg(short = 'f', long, help = "Where we should forward the websocket traffic?")]
-        forward_address: String,
+    Server(ServerArgs),
+    #[command(about = "Validate the config file and exit", long_about = None)]
+    CheckConfig,
+}
+
+// These arguments are also the [local] section of the config file, so every one of them is optional here.
+// Missing values are checked after the config file is merged.
+#[derive(clap::Args, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LocalArgs {
+    #[arg(short = 'l', long, env = "RWP_TCP_LISTEN_ADDRESS", value_delimiter = ',', value_parser = parse_mapping, help = "On what address we should listen and accept TCP connections? Can be repeated as name=address to expose several services")]
+    pub tcp_listen_address: Vec<Mapping>,
+    #[arg(short = 'u', long, env = "RWP_UDP_LISTEN_ADDRESS", value_delimiter = ',', value_parser = parse_mapping, help = "On what address we should listen and accept UDP datagrams? Can be repeated as name=address like TCP")]
+    pub udp_listen_address: Vec<Mapping>,
+    #[arg(long, env = "RWP_SOCKS5_LISTEN_ADDRESS", value_delimiter = ',', value_parser = parse_mapping, help = "On what address we should accept SOCKS5 clients? Their destination is dialed by the remote server if it's allowed there")]
+    pub socks5_listen_address: Vec<Mapping>,
+    #[arg(long, env = "RWP_HTTP_CONNECT", num_args = 0..=1, default_missing_value = "true", help = "Treat the TCP listeners as HTTP proxies which accept CONNECT requests. The remote server dials the requested target if it's allowed there")]
+    pub http_connect: Option<bool>,
+    #[arg(long, env = "RWP_ACCEPT_PROXY_PROTOCOL", num_args = 0..=1, default_missing_value = "true", help = "Read a PROXY protocol v1 or v2 header from the clients of the TCP listeners which connect from proxy_protocol_sources, like a load balancer. Other clients are treated as direct clients")]
+    pub accept_proxy_protocol: Option<bool>,
+    #[arg(long, env = "RWP_PROXY_PROTOCOL_SOURCES", value_delimiter = ',', help = "IP range of the load balancers which send the PROXY protocol header. Needed by accept_proxy_protocol. Can be repeated")]
+    pub proxy_protocol_sources: Vec<IpRange>,
+    #[arg(long, env = "RWP_UDP_IDLE_TIMEOUT", help = "After how many seconds of inactivity a UDP session is closed? Defaults to 60")]
+    pub udp_idle_timeout: Option<u64>,
+    #[arg(long, env = "RWP_JOIN_TIMEOUT", help = "After how many seconds a connection is closed if the remote server does not open its websocket? Defaults to 10")]
+    pub join_timeout: Option<u64>,
+    #[arg(long, env = "RWP_HOLD_TIMEOUT", help = "How many seconds a new connection can wait for the remote server to attach its controller? 0 closes it right away. Defaults to 10")]
+    pub hold_timeout: Option<u64>,
+    #[arg(long, env = "RWP_HOLD_QUEUE_LENGTH", help = "How many connections can wait for the controller at once? Defaults to 128")]
+    pub hold_queue_length: Option<usize>,
+    #[arg(long, env = "RWP_MAX_CONNECTIONS", help = "How many connections can be proxied at once? The new ones are rejected. Unlimited by default")]
+    pub max_connections: Option<usize>,
+    #[arg(long, env = "RWP_MAX_CONNECTIONS_PER_IP", help = "How many connections each client IP can have at once? Unlimited by default")]
+    pub max_connections_per_ip: Option<usize>,
+    #[arg(long, env = "RWP_CLIENT_ALLOW", value_delimiter = ',', help = "IP range like 10.0.0.0/8 which can connect to the listeners. Can be repeated. Everyone is allowed if not set")]
+    pub client_allow: Vec<IpRange>,
+    #[arg(long, env = "RWP_CLIENT_DENY", value_delimiter = ',', help = "IP range which cannot connect to the listeners even if it's allowed. Can be repeated")]
+    pub client_deny: Vec<IpRange>,
+    #[arg(long, env = "RWP_WEBSOCKET_ALLOW", value_delimiter = ',', help = "IP range which can open the websockets. Can be repeated. Everyone is allowed if not set")]
+    pub websocket_allow: Vec<IpRange>,
+    #[arg(long, env = "RWP_WEBSOCKET_DENY", value_delimiter = ',', help = "IP range which cannot open the websockets even if it's allowed. Can be repeated")]
+    pub websocket_deny: Vec<IpRange>,
+    #[arg(long, env = "RWP_TRUSTED_PROXIES", value_delimiter = ',', help = "IP range of the proxies like Cloudflare whose CF-Connecting-IP and X-Forwarded-For headers are believed. Can be repeated")]
+    pub trusted_proxies: Vec<IpRange>,
+    #[arg(short = 'c', long, env = "RWP_CLOUDFLARE_LISTEN_ADDRESS", help = "On what address we should listen and accept the connections from Cloudflare?")]
+    pub cloudflare_listen_address: Option<String>,
+    #[arg(short = 's', long, env = "RWP_SECRET", help = "Pre-shared secret which the remote server must present in order to use the websockets")]
+    pub secret: Option<String>,
+    #[arg(long, env = "RWP_TLS_CERTIFICATE", help = "PEM file of the certificate chain. If set with tls_key, the websockets are served over TLS")]
+    pub tls_certificate: Option<PathBuf>,
+    #[arg(long, env = "RWP_TLS_KEY", help = "PEM file of the private key of tls_certificate")]
+    pub tls_key: Option<PathBuf>,
+    #[arg(long, env = "RWP_TLS_CLIENT_CA", help = "PEM file of the CAs which must have signed the client certificate of the remote server. Requires TLS")]
+    pub tls_client_ca: Option<PathBuf>,
+    #[arg(long, env = "RWP_BASE_PATH", help = "Path prefix of the websocket endpoints, like /api/v2/stream")]
+    pub base_path: Option<String>,
+    #[arg(long, env = "RWP_METRICS_LISTEN_ADDRESS", help = "On what address we should serve the Prometheus metrics on /metrics? Disabled if not set")]
+    pub metrics_listen_address: Option<String>,
+    #[arg(long, env = "RWP_ADMIN_LISTEN_ADDRESS", help = "On what address we should serve the admin API? Disabled if not set")]
+    pub admin_listen_address: Option<String>,
+    #[command(flatten)]
+    #[serde(skip)]
+    pub tuning: TuningArgs,
+}
+
+// Like LocalArgs, these are also the [server] section of the config file
+#[derive(clap::Args, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerArgs {
+    #[arg(short = 'c', long, env = "RWP_CLOUDFLARE_SERVER_ADDRESS", help = "What is the address of cloudflare that we should send the websockets to?")]
+    pub cloudflare_server_address: Option<String>,
+    #[arg(long, env = "RWP_BASE_PATH", help = "Path prefix of the websocket endpoints which is added after the path of cloudflare_server_address")]
+    pub base_path: Option<String>,
+    #[arg(short = 'f', long, env = "RWP_FORWARD_ADDRESS", value_delimiter = ',', value_parser = parse_mapping, help = "Where we should forward the websocket traffic? Can be repeated as name=address to forward several services")]
+    pub forward_address: Vec<Mapping>,
+    #[arg(short = 'a', long, env = "RWP_ALLOW", value_delimiter = ',', help = "Which destinations can be requested dynamically, for example by SOCKS clients? Can be repeated. Looks like 10.0.0.0/8, [::1]:22, *.example.com:443 or *:8000-9000")]
+    pub allow: Vec<DestinationRule>,
+    #[arg(short = 's', long, env = "RWP_SECRET", help = "Pre-shared secret which is sent to the local server in every websocket handshake")]
+    pub secret: Option<String>,
+    #[arg(short = 'm', long, env = "RWP_MUX_CONNECTIONS", help = "How many websockets should be used to multiplex all connections? Zero (the default) means a websocket per connection")]
+    pub mux_connections: Option<usize>,
+    #[arg(long, env = "RWP_POOL_MIN", help = "How many idle websockets should be kept open ahead of time at least? Defaults to 0")]
+    pub pool_min: Option<usize>,
+    #[arg(long, env = "RWP_POOL_MAX", help = "How many idle websockets can be kept open when connections arrive fast? Defaults to pool_min")]
+    pub pool_max: Option<usize>,
+    #[arg(long, env = "RWP_TLS_CA", help = "PEM file of the CAs which are trusted for wss:// instead of the public ones")]
+    pub tls_ca: Option<PathBuf>,
+    #[arg(long, env = "RWP_TLS_PIN_SHA256", help = "Only trust the certificate with this SHA-256 fingerprint for wss://, even if it's self-signed")]
+    pub tls_pin_sha256: Option<String>,
+    #[arg(long, env = "RWP_TLS_SERVER_NAME", help = "Name which is sent in SNI and checked in the certificate instead of the host of cloudflare_server_address")]
+    pub tls_server_name: Option<String>,
+    #[arg(long, env = "RWP_TLS_CLIENT_CERTIFICATE", help = "PEM file of the client certificate chain which is presented for mutual TLS")]
+    pub tls_client_certificate: Option<PathBuf>,
+    #[arg(long, env = "RWP_TLS_CLIENT_KEY", help = "PEM file of the private key of tls_client_certificate")]
+    pub tls_client_key: Option<PathBuf>,
+    #[arg(short = 'H', long, env = "RWP_HEADER", value_delimiter = '\n', help = "Extra header which is sent in every websocket handshake, like \"CF-Access-Client-Id: abc\". Can be repeated and replaces the default headers such as Host or User-Agent")]
+    pub header: Vec<RequestHeader>,
+    #[arg(long, env = "RWP_MAX_DIALS", help = "How many targets can be dialed at once? The other connections fail right away. Unlimited by default")]
+    pub max_dials: Option<usize>,
+    #[arg(long, env = "RWP_PROXY_PROTOCOL", help = "Send a PROXY protocol header of this version (v1 or v2) to the forward targets, so they know the address of the original client")]
+    pub proxy_protocol: Option<Version>,
+    #[arg(long, env = "RWP_METRICS_LISTEN_ADDRESS", help = "On what address we should serve the Prometheus metrics on /metrics? Disabled if not set")]
+    pub metrics_listen_address: Option<String>,
+    #[arg(long, env = "RWP_ADMIN_LISTEN_ADDRESS", help = "On what address we should serve the admin API? Disabled if not set")]
+    pub admin_listen_address: Option<String>,
+    #[command(flatten)]
+    #[serde(skip)]
+    pub tuning: TuningArgs,
+}
+
+// The [tuning] section of the config file
+#[derive(clap::Args, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TuningArgs {
+    #[arg(long, env = "RWP_SOCKET_QUEUE_LENGTH", help = "How many packets can be queued between a socket and its websocket? Defaults to 32")]
+    pub socket_queue_length: Option<usize>,
+    #[arg(long, env = "RWP_READ_BUFFER_SIZE", help = "How many bytes are read from a socket at once? Defaults to 32768")]
+    pub read_buffer_size: Option<usize>,
+    #[arg(long, env = "RWP_RETRY_INTERVAL", help = "After how many seconds a failed websocket is dialed again? Defaults to 5")]
+    pub retry_interval: Option<u64>,
+    #[arg(long, env = "RWP_RETRY_MAX_INTERVAL", help = "Up to how many seconds the retry interval grows when the websocket keeps failing? Defaults to 60")]
+    pub retry_max_interval: Option<u64>,
+    #[arg(long, env = "RWP_DRAIN_TIMEOUT", help = "How many seconds the open connections can take to finish after SIGTERM or SIGINT? Defaults to 30")]
+    pub drain_timeout: Option<u64>,
+    #[arg(long, env = "RWP_HEARTBEAT_INTERVAL", help = "Every how many seconds the control websocket is pinged? Defaults to 15")]
+    pub heartbeat_interval: Option<u64>,
+    #[arg(long, env = "RWP_HEARTBEAT_TIMEOUT", help = "After how many seconds without hearing from the peer the control websocket is considered dead? Defaults to 45")]
+    pub heartbeat_timeout: Option<u64>,
+    #[arg(long, env = "RWP_GLOBAL_RATE_LIMIT", help = "Bandwidth limit of the whole process in bytes per second like 10M, or upload/download like 1M/10M. Unlimited by default")]
+    pub global_rate_limit: Option<RateLimit>,
+    #[arg(long, env = "RWP_LISTENER_RATE_LIMIT", value_delimiter = ',', help = "Bandwidth limit of each listener (service) like global_rate_limit. Can be repeated as service=limit to give a service its own limit; a limit without a service is used for the other listeners. Unlimited by default")]
+    #[serde(deserialize_with = "deserialize_listener_rate_limits")]
+    pub listener_rate_limit: Vec<ListenerRateLimit>,
+    #[arg(long, env = "RWP_CONNECTION_RATE_LIMIT", help = "Bandwidth limit of each connection like global_rate_limit. Unlimited by default")]
+    pub connection_rate_limit: Option<RateLimit>,
+    #[arg(long, env = "RWP_COMPRESSION", help = "Compress the data of the websockets with this algorithm. Only zstd is supported. It's used only if both sides enable it. Disabled by default")]
+    pub compression: Option<Algorithm>,
+    #[arg(long, env = "RWP_COMPRESSION_THRESHOLD", help = "Messages smaller than this many bytes are not compressed. Defaults to 1024")]
+    pub compression_threshold: Option<usize>,
+}
+
+/// An address which is associated with a service name
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Mapping {
+    pub name: String,
+    pub address: String,
+}
+
+impl TryFrom<String> for Mapping {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        parse_mapping(&value)
+    }
+}
+
+/// A header which is added to the websocket handshakes in the form of Name: value
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub struct RequestHeader {
+    pub name: HeaderName,
+    pub value: HeaderValue,
+}
+
+impl TryFrom<String> for RequestHeader {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl std::str::FromStr for RequestHeader {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (name, value) = value.split_once(':').ok_or("header must be like Name: value")?;
+        Ok(RequestHeader {
+            name: HeaderName::from_str(name.trim()).map_err(|err| format!("invalid header name: {err}"))?,
+            value: HeaderValue::from_str(value.trim()).map_err(|err| format!("invalid header value: {err}"))?,
+        })
+    }
+}
+
+/// A bandwidth limit in bytes per second like 10M, or upload/download like 1M/10M. 0 means unlimited.
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct RateLimit {
+    pub upload: u64,
+    pub download: u64,
+}
+
+impl TryFrom<String> for RateLimit {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl std::str::FromStr for RateLimit {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.split_once('/') {
+            Some((upload, download)) => Ok(RateLimit {
+                upload: parse_bytes(upload)?,
+                download: parse_bytes(download)?,
+            }),
+            None => {
+                let limit = parse_bytes(value)?;
+                Ok(RateLimit {
+                    upload: limit,
+                    download: limit,
+                })
+            }
+        }
+    }
+}
+
+impl From<RateLimit> for String {
+    fn from(limit: RateLimit) -> Self {
+        if limit.upload == limit.download {
+            format_bytes(limit.upload)
+        } else {
+            format!("{}/{}", format_bytes(limit.upload), format_bytes(limit.download))
+        }
+    }
+}
+
+/// The rate limit of a listener, written as service=limit.
+/// Without a service, it's the limit of the listeners which do not have their own.
+#[derive(Debug, Clone)]
+pub struct ListenerRateLimit {
+    pub service: Option<String>,
+    pub limit: RateLimit,
+}
+
+impl std::str::FromStr for ListenerRateLimit {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.split_once('=') {
+            Some(("", _)) => Err("service name cannot be empty".to_owned()),
+            Some((OTHER_LISTENERS, limit)) => Ok(ListenerRateLimit {
+                service: None,
+                limit: limit.parse()?,
+            }),
+            Some((service, limit)) => Ok(ListenerRateLimit {
+                service: Some(service.to_owned()),
+                limit: limit.parse()?,
+            }),
+            None => Ok(ListenerRateLimit {
+                service: None,
+                limit: value.parse()?,
+            }),
+        }
     }
-}
\ No newline at end of file
+}
+
+/// The name which stands for the listeners without their own limit
+const OTHER_LISTENERS: &str = "*";
+
+/// In the config file, the limits of the listeners are either a single limit for all of them
+/// or a table like { ssh = "1M", "*" = "10M" }
+fn deserialize_listener_rate_limits<'de, D>(deserializer: D) -> Result<Vec<ListenerRateLimit>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Limits {
+        All(RateLimit),
+        ByService(std::collections::BTreeMap<String, RateLimit>),
+    }
+    let limits = match Limits::deserialize(deserializer)? {
+        Limits::All(limit) => vec![ListenerRateLimit {
+            service: None,
+            limit,
+        }],
+        Limits::ByService(limits) => limits
+            .into_iter()
+            .map(|(service, limit)| ListenerRateLimit {
+                service: (service != OTHER_LISTENERS).then_some(service),
+                limit,
+            })
+            .collect(),
+    };
+    Ok(limits)
+}
+
+/// The suffixes of the byte counts
+const BYTE_UNITS: [(char, u64); 3] = [('G', 1 << 30), ('M', 1 << 20), ('K', 1 << 10)];
+
+/// Parses a byte count like 512, 64K, 10M or 1G
+fn parse_bytes(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let (number, unit) = match value.chars().last().map(|last| last.to_ascii_uppercase()) {
+        Some(last) if last.is_ascii_alphabetic() => {
+            let unit = BYTE_UNITS
+                .iter()
+                .find(|(suffix, _)| *suffix == last)
+                .map(|(_, unit)| *unit)
+                .ok_or_else(|| format!("unknown unit in {value}, use K, M or G"))?;
+            (&value[..value.len() - 1], unit)
+        }
+        _ => (value, 1),
+    };
+    let number: u64 = number.parse().map_err(|_| format!("invalid byte count: {value}"))?;
+    number.checked_mul(unit).ok_or_else(|| format!("byte count is too big: {value}"))
+}
+
+/// Writes a byte count with the biggest unit which divides it
+fn format_bytes(value: u64) -> String {
+    for (suffix, unit) in BYTE_UNITS {
+        if value != 0 && value.is_multiple_of(unit) {
+            return format!("{}{suffix}", value / unit);
+        }
+    }
+    value.to_string()
+}
+
+/// Parses a mapping in the form of name=address or just address for the default service
+fn parse_mapping(value: &str) -> Result<Mapping, String> {
+    match value.split_once('=') {
+        Some(("", _)) => Err("service name cannot be empty".to_owned()),
+        Some((_, "")) => Err("address cannot be empty".to_owned()),
+        Some((name, address)) => Ok(Mapping {
+            name: name.to_owned(),
+            address: address.to_owned(),
+        }),
+        None => Ok(Mapping {
+            name: DEFAULT_SERVICE.to_owned(),
+            address: value.to_owned(),
+        }),
+    }
+}
@@ -0,0 +1,197 @@
+//! Optional compression of the data in the websockets of the connections.
+//!
+//! The remote server asks for it with a header in the handshake of /connect, and the local server
+//! answers with the same header if it has enabled it too. Then each data message starts with a byte
+//! which tells whether the rest is compressed. Empty messages still mean EOF, so they have no flag.
+//! Small messages are sent as is, so the interactive traffic does not pay for the compression.
+
+use std::io::{self, Read};
+use std::str::FromStr;
+
+use serde::Deserialize;
+
+use crate::config::tuning;
+use crate::metrics;
+
+/// The handshake header which carries the name of the algorithm
+pub(crate) const HEADER: &str = "rwp-compression";
+
+/// The flag of a message which is sent as is
+const FLAG_RAW: u8 = 0;
+/// The flag of a message which is compressed with zstd
+const FLAG_ZSTD: u8 = 1;
+/// A decompressed message can't be bigger than this
+const MAX_MESSAGE_SIZE: u64 = 16 * 1024 * 1024;
+
+/// How the data is compressed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum Algorithm {
+    Zstd,
+}
+
+impl Algorithm {
+    /// The name which is sent in the handshake header
+    pub fn name(self) -> &'static str {
+        match self {
+            Algorithm::Zstd => "zstd",
+        }
+    }
+}
+
+impl FromStr for Algorithm {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "zstd" => Ok(Algorithm::Zstd),
+            _ => Err(format!("unknown compression {value}, use zstd")),
+        }
+    }
+}
+
+impl TryFrom<String> for Algorithm {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+/// Returns the value of the header which we send, or None if the compression is disabled
+pub(crate) fn offer() -> Option<&'static str> {
+    tuning().compression.map(Algorithm::name)
+}
+
+/// Returns true if the header of the peer has the algorithm which we have enabled
+pub(crate) fn negotiate(header: Option<&str>) -> bool {
+    match (tuning().compression, header) {
+        (Some(algorithm), Some(header)) => header
+            .split(',')
+            .any(|name| name.trim().eq_ignore_ascii_case(algorithm.name())),
+        _ => false,
+    }
+}
+
+/// Makes a data message out of the data which is read from a socket
+pub(crate) fn compress(data: Vec<u8>) -> Vec<u8> {
+    if data.is_empty() {
+        return data; // EOF
+    }
+    let mut message = Vec::new();
+    // The peer can't decompress the huge messages, so they are sent as is
+    if data.len() >= tuning().compression_threshold && data.len() as u64 <= MAX_MESSAGE_SIZE {
+        message.push(FLAG_ZSTD);
+        if zstd::stream::copy_encode(data.as_slice(), &mut message, 0).is_err() {
+            message.clear();
+        }
+    }
+    // Incompressible data is sent as is
+    if message.is_empty() || message.len() > data.len() {
+        message.clear();
+        message.reserve_exact(data.len() + 1);
+        message.push(FLAG_RAW);
+        message.extend_from_slice(&data);
+    }
+    metrics::COMPRESSION_INPUT.add(data.len() as u64);
+    metrics::COMPRESSION_OUTPUT.add(message.len() as u64);
+    message
+}
+
+/// Reverts compress on a data message which is received from the websocket
+pub(crate) fn decompress(mut message: Vec<u8>) -> io::Result<Vec<u8>> {
+    let flag = match message.first() {
+        Some(flag) => *flag,
+        None => return Ok(message), // EOF
+    };
+    match flag {
+        FLAG_RAW => {
+            message.remove(0);
+            Ok(message)
+        }
+        FLAG_ZSTD => {
+            // Read one more byte than the limit, so a bigger message is an error instead of being cut
+            let mut data = Vec::new();
+            zstd::stream::read::Decoder::new(&message[1..])?
+                .take(MAX_MESSAGE_SIZE + 1)
+                .read_to_end(&mut data)?;
+            if data.len() as u64 > MAX_MESSAGE_SIZE {
+                return Err(invalid_data("decompressed message is too big"));
+            }
+            Ok(data)
+        }
+        _ => Err(invalid_data("unknown compression flag")),
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Makes a message like the peer would, without the threshold and the size checks of compress
+    fn zstd_message(data: &[u8]) -> Vec<u8> {
+        let mut message = vec![FLAG_ZSTD];
+        zstd::stream::copy_encode(data, &mut message, 0).unwrap();
+        message
+    }
+
+    #[test]
+    fn round_trip() {
+        let small = b"ls -la\n".to_vec();
+        let compressible = b"hello world ".repeat(1024);
+        let incompressible: Vec<u8> = (0..4096).map(|_| rand::random::<u8>()).collect();
+        for data in [small, compressible, incompressible] {
+            assert_eq!(decompress(compress(data.clone())).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn flags() {
+        // Small messages are below the threshold
+        let message = compress(b"ls -la\n".to_vec());
+        assert_eq!(message, b"\0ls -la\n");
+        let data = b"hello world ".repeat(1024);
+        let message = compress(data.clone());
+        assert_eq!(message[0], FLAG_ZSTD);
+        assert!(message.len() < data.len());
+        // Random data does not get smaller, so it's sent as is
+        let data: Vec<u8> = (0..4096).map(|_| rand::random::<u8>()).collect();
+        let message = compress(data.clone());
+        assert_eq!(message[0], FLAG_RAW);
+        assert_eq!(&message[1..], data.as_slice());
+    }
+
+    #[test]
+    fn eof() {
+        assert!(compress(Vec::new()).is_empty());
+        assert!(decompress(Vec::new()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn invalid_messages() {
+        let error = decompress(vec![2, 1, 2, 3]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(decompress(vec![FLAG_ZSTD, 1, 2, 3]).is_err());
+    }
+
+    #[test]
+    fn message_size_limit() {
+        let limit = MAX_MESSAGE_SIZE as usize;
+        // A message at the limit is fine
+        let message = zstd_message(&vec![0; limit]);
+        assert_eq!(decompress(message).unwrap().len(), limit);
+        // A zstd bomb is rejected instead of being cut
+        let message = zstd_message(&vec![0; limit + 1]);
+        assert!(message.len() < 1024);
+        let error = decompress(message).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        // So the sender does not compress the huge messages
+        let message = compress(vec![0; limit + 1]);
+        assert_eq!(message[0], FLAG_RAW);
+        assert_eq!(message.len(), limit + 2);
+    }
+}
@@ -0,0 +1,481 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use serde::Deserialize;
+use url::Url;
+
+use crate::arguments::{LocalArgs, Mapping, RateLimit, RequestHeader, ServerArgs, TuningArgs};
+use crate::compression::Algorithm;
+use crate::local::access::{AccessRules, IpRange};
+use crate::local::{self, tls::TlsFiles};
+use crate::proxy_protocol::Version;
+use crate::remote::allowlist::DestinationRule;
+use crate::remote::tls::{self, TlsOptions};
+
+/// The TOML config file. Each section has the same keys as the long command line flags.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    local: Option<LocalArgs>,
+    server: Option<ServerArgs>,
+    #[serde(default)]
+    tuning: TuningArgs,
+}
+
+/// The settings of the local server after merging the command line and the config file
+pub struct LocalConfig {
+    pub cloudflare_listen_address: String,
+    pub tcp_listen_addresses: Vec<Mapping>,
+    pub udp_listen_addresses: Vec<Mapping>,
+    pub socks5_listen_addresses: Vec<Mapping>,
+    pub http_connect: bool,
+    /// Should the clients of the TCP listeners start with a PROXY protocol header?
+    pub accept_proxy_protocol: bool,
+    /// The load balancers which can send the PROXY protocol header
+    pub proxy_protocol_sources: Vec<IpRange>,
+    pub udp_idle_timeout: Duration,
+    /// How long a connection can wait for the remote server to open its websocket
+    pub join_timeout: Duration,
+    /// How long a new connection can wait for the controller to attach
+    pub hold_timeout: Duration,
+    /// How many connections can wait for the controller at once
+    pub hold_queue_length: usize,
+    /// How many connections can be proxied at once in total and from each IP. 0 means unlimited.
+    pub max_connections: usize,
+    pub max_connections_per_ip: usize,
+    /// Who can connect to the listeners
+    pub client_access: AccessRules,
+    /// Who can open the websockets
+    pub websocket_access: AccessRules,
+    /// The proxies whose headers tell the address of the websocket peers
+    pub trusted_proxies: Vec<IpRange>,
+    pub secret: Option<String>,
+    /// If set, the websockets are served under this path like /api/v2/stream
+    pub base_path: Option<String>,
+    /// If set, the websockets are served over TLS
+    pub tls: Option<TlsFiles>,
+    /// If set, the Prometheus metrics are served on this address
+    pub metrics_listen_address: Option<String>,
+    /// If set, the admin API is served on this address
+    pub admin_listen_address: Option<String>,
+}
+
+/// The settings of the remote server after merging the command line and the config file
+pub struct ServerConfig {
+    /// The address of the local server including the base path
+    pub cloudflare_server_address: Url,
+    pub forward_addresses: Vec<Mapping>,
+    pub allowed_destinations: Vec<DestinationRule>,
+    pub secret: Option<String>,
+    pub mux_connections: usize,
+    pub pool_min: usize,
+    pub pool_max: usize,
+    /// How wss:// addresses are dialed
+    pub tls: TlsOptions,
+    /// Extra headers of the websocket handshakes
+    pub headers: Vec<RequestHeader>,
+    /// How many targets can be dialed at once. 0 means unlimited.
+    pub max_dials: usize,
+    /// If set, this PROXY protocol header is sent to the forward targets
+    pub proxy_protocol: Option<Version>,
+    /// If set, the Prometheus metrics are served on this address
+    pub metrics_listen_address: Option<String>,
+    /// If set, the admin API is served on this address
+    pub admin_listen_address: Option<String>,
+}
+
+/// Buffers and timings which both sides use all over the place
+#[derive(Debug)]
+pub struct Tuning {
+    /// How many packets can be queued between a socket and its websocket
+    pub socket_queue_length: usize,
+    /// How big is our read buffer size
+    pub read_buffer_size: usize,
+    /// How long to wait before dialing a failed websocket again
+    pub retry_interval: Duration,
+    /// How long the retry interval can grow when the websocket keeps failing
+    pub retry_max_interval: Duration,
+    /// How long the open connections can take to finish when shutting down
+    pub drain_timeout: Duration,
+    /// How often the control websocket is pinged
+    pub heartbeat_interval: Duration,
+    /// How long the control websocket can be silent before it's considered dead
+    pub heartbeat_timeout: Duration,
+    /// Initial bandwidth limits of the whole process, each listener and each connection
+    pub global_rate_limit: RateLimit,
+    pub listener_rate_limit: RateLimit,
+    pub connection_rate_limit: RateLimit,
+    /// The listeners which have their own limit instead of listener_rate_limit, by their service name
+    pub service_rate_limits: HashMap<String, RateLimit>,
+    /// If set and the peer agrees, the data of the websockets is compressed
+    pub compression: Option<Algorithm>,
+    /// The smaller messages are not compressed
+    pub compression_threshold: usize,
+}
+
+impl Default for Tuning {
+    fn default() -> Self {
+        Tuning {
+            socket_queue_length: 32,
+            read_buffer_size: 32 * 1024,
+            retry_interval: Duration::from_secs(5),
+            retry_max_interval: Duration::from_secs(60),
+            drain_timeout: Duration::from_secs(30),
+            heartbeat_interval: Duration::from_secs(15),
+            heartbeat_timeout: Duration::from_secs(45),
+            global_rate_limit: RateLimit::default(),
+            listener_rate_limit: RateLimit::default(),
+            connection_rate_limit: RateLimit::default(),
+            service_rate_limits: HashMap::new(),
+            compression: None,
+            compression_threshold: 1024,
+        }
+    }
+}
+
+/// The tuning is needed deep inside the proxies, so it's simply global
+static TUNING: OnceLock<Tuning> = OnceLock::new();
+
+/// Returns the tuning of the program. Defaults are used if it was never set.
+pub(crate) fn tuning() -> &'static Tuning {
+    TUNING.get_or_init(Tuning::default)
+}
+
+/// Sets the tuning of the program. Must be called before anything uses it.
+pub(crate) fn set_tuning(tuning: Tuning) {
+    TUNING.set(tuning).expect("tuning is already set");
+}
+
+impl ConfigFile {
+    /// Reads the config file. No path means an empty config.
+    pub fn load(path: Option<&Path>) -> Result<ConfigFile, String> {
+        let path = match path {
+            Some(path) => path,
+            None => return Ok(ConfigFile::default()),
+        };
+        let content = std::fs::read_to_string(path)
+            .map_err(|err| format!("cannot read config file {}: {err}", path.display()))?;
+        toml::from_str(&content)
+            .map_err(|err| format!("invalid config file {}: {err}", path.display()))
+    }
+
+    /// Merges the local section with the command line. The command line wins.
+    pub fn local(&mut self, args: LocalArgs) -> Result<(LocalConfig, Tuning), String> {
+        let file = self.local.take().unwrap_or_default();
+        let tls_client_ca = args.tls_client_ca.or(file.tls_client_ca);
+        let config = LocalConfig {
+            cloudflare_listen_address: args
+                .cloudflare_listen_address
+                .or(file.cloudflare_listen_address)
+                .ok_or("cloudflare_listen_address is not set")?,
+            tcp_listen_addresses: merge_list(args.tcp_listen_address, file.tcp_listen_address),
+            udp_listen_addresses: merge_list(args.udp_listen_address, file.udp_listen_address),
+            socks5_listen_addresses: merge_list(
+                args.socks5_listen_address,
+                file.socks5_listen_address,
+            ),
+            http_connect: args.http_connect.or(file.http_connect).unwrap_or(false),
+            accept_proxy_protocol: args
+                .accept_proxy_protocol
+                .or(file.accept_proxy_protocol)
+                .unwrap_or(false),
+            proxy_protocol_sources: merge_list(
+                args.proxy_protocol_sources,
+                file.proxy_protocol_sources,
+            ),
+            udp_idle_timeout: Duration::from_secs(
+                args.udp_idle_timeout
+                    .or(file.udp_idle_timeout)
+                    .unwrap_or(60),
+            ),
+            join_timeout: Duration::from_secs(
+                args.join_timeout.or(file.join_timeout).unwrap_or(10),
+            ),
+            hold_timeout: Duration::from_secs(
+                args.hold_timeout.or(file.hold_timeout).unwrap_or(10),
+            ),
+            hold_queue_length: args
+                .hold_queue_length
+                .or(file.hold_queue_length)
+                .unwrap_or(128),
+            max_connections: args.max_connections.or(file.max_connections).unwrap_or(0),
+            max_connections_per_ip: args
+                .max_connections_per_ip
+                .or(file.max_connections_per_ip)
+                .unwrap_or(0),
+            client_access: AccessRules {
+                allow: merge_list(args.client_allow, file.client_allow),
+                deny: merge_list(args.client_deny, file.client_deny),
+            },
+            websocket_access: AccessRules {
+                allow: merge_list(args.websocket_allow, file.websocket_allow),
+                deny: merge_list(args.websocket_deny, file.websocket_deny),
+            },
+            trusted_proxies: merge_list(args.trusted_proxies, file.trusted_proxies),
+            secret: args.secret.or(file.secret),
+            base_path: normalize_base_path(args.base_path.or(file.base_path)),
+            tls: match (
+                args.tls_certificate.or(file.tls_certificate),
+                args.tls_key.or(file.tls_key),
+            ) {
+                (Some(certificate), Some(key)) => Some(TlsFiles {
+                    certificate,
+                    key,
+                    client_ca: tls_client_ca,
+                }),
+                (None, None) if tls_client_ca.is_some() => {
+                    return Err("tls_client_ca needs tls_certificate and tls_key".to_owned())
+                }
+                (None, None) => None,
+                _ => return Err("tls_certificate and tls_key must be set together".to_owned()),
+            },
+            metrics_listen_address: args.metrics_listen_address.or(file.metrics_listen_address),
+            admin_listen_address: args.admin_listen_address.or(file.admin_listen_address),
+        };
+        if config.tcp_listen_addresses.is_empty()
+            && config.udp_listen_addresses.is_empty()
+            && config.socks5_listen_addresses.is_empty()
+        {
+            return Err("at least one tcp, udp or socks5 listen address is needed".to_owned());
+        }
+        if config.accept_proxy_protocol && config.proxy_protocol_sources.is_empty() {
+            return Err("accept_proxy_protocol needs proxy_protocol_sources".to_owned());
+        }
+        Ok((config, self.tuning(args.tuning)?))
+    }
+
+    /// Merges the server section with the command line. The command line wins.
+    pub fn server(&mut self, args: ServerArgs) -> Result<(ServerConfig, Tuning), String> {
+        let file = self.server.take().unwrap_or_default();
+        let pool_min = args.pool_min.or(file.pool_min).unwrap_or(0);
+        let config = ServerConfig {
+            cloudflare_server_address: server_url(
+                &args
+                    .cloudflare_server_address
+                    .or(file.cloudflare_server_address)
+                    .ok_or("cloudflare_server_address is not set")?,
+                normalize_base_path(args.base_path.or(file.base_path)),
+            )?,
+            forward_addresses: merge_list(args.forward_address, file.forward_address),
+            allowed_destinations: merge_list(args.allow, file.allow),
+            secret: args.secret.or(file.secret),
+            mux_connections: args.mux_connections.or(file.mux_connections).unwrap_or(0),
+            pool_min,
+            pool_max: args.pool_max.or(file.pool_max).unwrap_or(pool_min),
+            tls: TlsOptions {
+                ca: args.tls_ca.or(file.tls_ca),
+                pin_sha256: args
+                    .tls_pin_sha256
+                    .or(file.tls_pin_sha256)
+                    .map(|pin| tls::parse_fingerprint(&pin))
+                    .transpose()?,
+                server_name: args.tls_server_name.or(file.tls_server_name),
+                client_identity: match (
+                    args.tls_client_certificate.or(file.tls_client_certificate),
+                    args.tls_client_key.or(file.tls_client_key),
+                ) {
+                    (Some(certificate), Some(key)) => Some((certificate, key)),
+                    (None, None) => None,
+                    _ => {
+                        return Err(
+                            "tls_client_certificate and tls_client_key must be set together"
+                                .to_owned(),
+                        )
+                    }
+                },
+            },
+            headers: merge_list(args.header, file.header),
+            max_dials: args.max_dials.or(file.max_dials).unwrap_or(0),
+            proxy_protocol: args.proxy_protocol.or(file.proxy_protocol),
+            metrics_listen_address: args.metrics_listen_address.or(file.metrics_listen_address),
+            admin_listen_address: args.admin_listen_address.or(file.admin_listen_address),
+        };
+        if config.tls.ca.is_some() && config.tls.pin_sha256.is_some() {
+            return Err("tls_ca and tls_pin_sha256 cannot be used together".to_owned());
+        }
+        if config.forward_addresses.is_empty() && config.allowed_destinations.is_empty() {
+            return Err("at least one forward address or allow rule is needed".to_owned());
+        }
+        if config.pool_max < config.pool_min {
+            return Err(format!(
+                "maximum pool size ({}) is less than the minimum ({})",
+                config.pool_max, config.pool_min
+            ));
+        }
+        Ok((config, self.tuning(args.tuning)?))
+    }
+
+    /// Validates every section of the config file without any command line
+    pub fn check(mut self) -> Result<(), String> {
+        if self.local.is_none() && self.server.is_none() {
+            return Err("config file has neither a [local] nor a [server] section".to_owned());
+        }
+        if self.local.is_some() {
+            let (config, _) = self
+                .local(LocalArgs::default())
+                .map_err(|err| format!("[local]: {err}"))?;
+            // The certificate is loaded when the local server starts, so check it here too
+            if let Some(files) = &config.tls {
+                local::tls::load(files).map_err(|err| format!("[local]: {err}"))?;
+            }
+        }
+        if self.server.is_some() {
+            let (config, _) = self
+                .server(ServerArgs::default())
+                .map_err(|err| format!("[server]: {err}"))?;
+            // So are the CA and the client certificate of the remote server
+            tls::TlsClient::new(config.tls).map_err(|err| format!("[server]: {err}"))?;
+        }
+        Ok(())
+    }
+
+    fn tuning(&self, args: TuningArgs) -> Result<Tuning, String> {
+        let file = &self.tuning;
+        let default = Tuning::default();
+        let mut tuning = Tuning {
+            socket_queue_length: args
+                .socket_queue_length
+                .or(file.socket_queue_length)
+                .unwrap_or(default.socket_queue_length),
+            read_buffer_size: args
+                .read_buffer_size
+                .or(file.read_buffer_size)
+                .unwrap_or(default.read_buffer_size),
+            retry_interval: args
+                .retry_interval
+                .or(file.retry_interval)
+                .map(Duration::from_secs)
+                .unwrap_or(default.retry_interval),
+            retry_max_interval: args
+                .retry_max_interval
+                .or(file.retry_max_interval)
+                .map(Duration::from_secs)
+                .unwrap_or(default.retry_max_interval),
+            drain_timeout: args
+                .drain_timeout
+                .or(file.drain_timeout)
+                .map(Duration::from_secs)
+                .unwrap_or(default.drain_timeout),
+            heartbeat_interval: args
+                .heartbeat_interval
+                .or(file.heartbeat_interval)
+                .map(Duration::from_secs)
+                .unwrap_or(default.heartbeat_interval),
+            heartbeat_timeout: args
+                .heartbeat_timeout
+                .or(file.heartbeat_timeout)
+                .map(Duration::from_secs)
+                .unwrap_or(default.heartbeat_timeout),
+            global_rate_limit: args
+                .global_rate_limit
+                .or(file.global_rate_limit)
+                .unwrap_or(default.global_rate_limit),
+            listener_rate_limit: default.listener_rate_limit,
+            connection_rate_limit: args
+                .connection_rate_limit
+                .or(file.connection_rate_limit)
+                .unwrap_or(default.connection_rate_limit),
+            service_rate_limits: default.service_rate_limits,
+            compression: args.compression.or(file.compression),
+            compression_threshold: args
+                .compression_threshold
+                .or(file.compression_threshold)
+                .unwrap_or(default.compression_threshold),
+        };
+        for limit in merge_list(args.listener_rate_limit, file.listener_rate_limit.clone()) {
+            match limit.service {
+                Some(service) => {
+                    tuning.service_rate_limits.insert(service, limit.limit);
+                }
+                None => tuning.listener_rate_limit = limit.limit,
+            }
+        }
+        if tuning.socket_queue_length == 0 || tuning.read_buffer_size == 0 {
+            return Err("socket_queue_length and read_buffer_size must be positive".to_owned());
+        }
+        if tuning.retry_max_interval < tuning.retry_interval {
+            return Err("retry_max_interval cannot be shorter than retry_interval".to_owned());
+        }
+        if tuning.heartbeat_interval.is_zero() {
+            return Err("heartbeat_interval must be positive".to_owned());
+        }
+        if tuning.heartbeat_timeout <= tuning.heartbeat_interval {
+            return Err("heartbeat_timeout must be longer than heartbeat_interval".to_owned());
+        }
+        Ok(tuning)
+    }
+}
+
+/// Lists are not merged; if the command line has any item, the list of the config file is ignored
+fn merge_list<T>(args: Vec<T>, file: Vec<T>) -> Vec<T> {
+    if args.is_empty() {
+        file
+    } else {
+        args
+    }
+}
+
+/// Makes sure that the base path starts with a slash and does not end with one.
+/// An empty base path is the root.
+fn normalize_base_path(base_path: Option<String>) -> Option<String> {
+    let base_path = base_path?;
+    let base_path = base_path.trim_matches('/');
+    if base_path.is_empty() {
+        None
+    } else {
+        Some(format!("/{base_path}"))
+    }
+}
+
+/// Parses the address of the local server and appends the base path to its path.
+/// The query string is kept, so every endpoint gets it too.
+fn server_url(address: &str, base_path: Option<String>) -> Result<Url, String> {
+    let mut url =
+        Url::parse(address).map_err(|err| format!("invalid cloudflare_server_address: {err}"))?;
+    if url.scheme() != "ws" && url.scheme() != "wss" {
+        return Err("cloudflare_server_address must be a ws:// or wss:// address".to_owned());
+    }
+    if let Some(base_path) = base_path {
+        url.path_segments_mut()
+            .map_err(|_| "cloudflare_server_address cannot have a path")?
+            .pop_if_empty()
+            .extend(base_path.split('/').filter(|segment| !segment.is_empty()));
+    }
+    Ok(url)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn base_path(value: &str) -> Option<String> {
+        normalize_base_path(Some(value.to_owned()))
+    }
+
+    #[test]
+    fn base_paths() {
+        assert_eq!(normalize_base_path(None), None);
+        assert_eq!(base_path(""), None);
+        assert_eq!(base_path("/"), None);
+        assert_eq!(base_path("api/v2/"), Some("/api/v2".to_owned()));
+        assert_eq!(base_path("/api/v2"), Some("/api/v2".to_owned()));
+        assert_eq!(base_path("//api//"), Some("/api".to_owned()));
+    }
+
+    #[test]
+    fn server_urls() {
+        let url = |address: &str, base: &str| server_url(address, base_path(base)).unwrap();
+        assert_eq!(url("ws://h/", "").as_str(), "ws://h/");
+        assert_eq!(url("ws://h", "").as_str(), "ws://h/");
+        assert_eq!(url("ws://h/", "/api/v2/").as_str(), "ws://h/api/v2");
+        assert_eq!(
+            url("ws://h/prefix/?a=b", "/api/v2/").as_str(),
+            "ws://h/prefix/api/v2?a=b"
+        );
+        assert_eq!(url("wss://h/prefix?a=b", "").as_str(), "wss://h/prefix?a=b");
+        assert!(server_url("http://h/", None).is_err());
+        assert!(server_url("h:8080", None).is_err());
+    }
+}
@@ -0,0 +1,214 @@
+//! CIDR rules which decide who can connect to the local server.
+//!
+//! The clients of the listeners are checked by their socket address.
+//! The websocket peers are usually behind Cloudflare, so their address is read from
+//! CF-Connecting-IP or X-Forwarded-For, but only if the request came from a trusted proxy.
+
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+use axum::extract::{ConnectInfo, Request, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use ipnet::IpNet;
+use log::warn;
+use serde::Deserialize;
+
+use super::LocalState;
+
+/// An IP range like 10.0.0.0/8 or a single IP like 192.0.2.1
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(try_from = "String")]
+pub struct IpRange(IpNet);
+
+impl IpRange {
+    fn contains(&self, ip: IpAddr) -> bool {
+        // Compare the IPv4 mapped IPv6 addresses as IPv4
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+            IpAddr::V4(_) => ip,
+        };
+        self.0.contains(&ip)
+    }
+}
+
+impl FromStr for IpRange {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match IpAddr::from_str(value) {
+            Ok(ip) => Ok(IpRange(IpNet::from(ip))),
+            Err(_) => IpNet::from_str(value)
+                .map(IpRange)
+                .map_err(|_| format!("invalid IP range: {value}")),
+        }
+    }
+}
+
+impl TryFrom<String> for IpRange {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+/// Allow and deny lists of IP ranges
+#[derive(Debug, Default)]
+pub struct AccessRules {
+    /// If not empty, only these ranges are allowed
+    pub allow: Vec<IpRange>,
+    /// These ranges are rejected even if they are allowed
+    pub deny: Vec<IpRange>,
+}
+
+impl AccessRules {
+    /// Returns true if the IP can connect
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        if self.deny.iter().any(|range| range.contains(ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|range| range.contains(ip))
+    }
+}
+
+/// Returns true if the IP is in the ranges of the trusted proxies or load balancers
+pub(crate) fn is_trusted(trusted: &[IpRange], ip: IpAddr) -> bool {
+    trusted.iter().any(|range| range.contains(ip))
+}
+
+/// Middleware which rejects the websockets of the peers which are not allowed
+pub(crate) async fn require_allowed_peer(
+    State(state): State<&'static LocalState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    request: Request,
+    next: Next,
+) -> Response {
+    let ip = real_ip(peer.ip(), &headers, &state.trusted_proxies);
+    if state.websocket_access.allows(ip) {
+        next.run(request).await
+    } else {
+        warn!("Rejected websocket of {ip} to {}", request.uri().path());
+        StatusCode::FORBIDDEN.into_response()
+    }
+}
+
+/// Finds out the address of the client which the request came from.
+/// The headers are only believed if the peer is a trusted proxy.
+fn real_ip(peer: IpAddr, headers: &HeaderMap, trusted_proxies: &[IpRange]) -> IpAddr {
+    let trusted = |ip: IpAddr| is_trusted(trusted_proxies, ip);
+    if !trusted(peer) {
+        return peer;
+    }
+    // Cloudflare puts the client address in its own header
+    let connecting_ip = headers
+        .get("cf-connecting-ip")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse().ok());
+    if let Some(ip) = connecting_ip {
+        return ip;
+    }
+    // Every proxy appends the address of its peer, so the client is the last one which is not trusted
+    let forwarded: Vec<IpAddr> = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|ip| ip.trim().parse().ok())
+        .collect();
+    forwarded
+        .iter()
+        .rev()
+        .find(|ip| !trusted(**ip))
+        .or(forwarded.first())
+        .copied()
+        .unwrap_or(peer)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    fn ranges(values: &[&str]) -> Vec<IpRange> {
+        values.iter().map(|value| value.parse().unwrap()).collect()
+    }
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    fn headers(values: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in values {
+            headers.append(*name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    #[test]
+    fn untrusted_peer_headers_are_ignored() {
+        let trusted = ranges(&["10.0.0.0/8"]);
+        let headers = headers(&[
+            ("cf-connecting-ip", "198.51.100.7"),
+            ("x-forwarded-for", "198.51.100.8"),
+        ]);
+        assert_eq!(
+            real_ip(ip("192.0.2.1"), &headers, &trusted),
+            ip("192.0.2.1")
+        );
+    }
+
+    #[test]
+    fn trusted_peer_headers() {
+        let trusted = ranges(&["10.0.0.0/8"]);
+        let cloudflare = headers(&[
+            ("cf-connecting-ip", "198.51.100.7"),
+            ("x-forwarded-for", "198.51.100.8"),
+        ]);
+        assert_eq!(
+            real_ip(ip("10.0.0.1"), &cloudflare, &trusted),
+            ip("198.51.100.7")
+        );
+        assert_eq!(
+            real_ip(ip("10.0.0.1"), &HeaderMap::new(), &trusted),
+            ip("10.0.0.1")
+        );
+    }
+
+    #[test]
+    fn forwarded_chain() {
+        let trusted = ranges(&["10.0.0.0/8"]);
+        // The client can put anything at the start, only the last untrusted address is believed
+        let chain = headers(&[
+            ("x-forwarded-for", "203.0.113.1, 198.51.100.7"),
+            ("x-forwarded-for", "10.0.0.2"),
+        ]);
+        assert_eq!(
+            real_ip(ip("10.0.0.1"), &chain, &trusted),
+            ip("198.51.100.7")
+        );
+        let all_trusted = headers(&[("x-forwarded-for", "10.0.0.3, 10.0.0.2")]);
+        assert_eq!(
+            real_ip(ip("10.0.0.1"), &all_trusted, &trusted),
+            ip("10.0.0.3")
+        );
+    }
+
+    #[test]
+    fn access_rules() {
+        let rules = AccessRules {
+            allow: ranges(&["192.0.2.0/24"]),
+            deny: ranges(&["192.0.2.66"]),
+        };
+        assert!(rules.allows(ip("192.0.2.1")));
+        assert!(rules.allows(ip("::ffff:192.0.2.1")));
+        assert!(!rules.allows(ip("192.0.2.66")));
+        assert!(!rules.allows(ip("198.51.100.1")));
+        assert!(AccessRules::default().allows(ip("198.51.100.1")));
+        assert!("192.0.2.0/33".parse::<IpRange>().is_err());
+    }
+}
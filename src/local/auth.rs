@@ -0,0 +1,53 @@
+use std::collections::HashMap;
+
+use axum::extract::{Query, Request, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use log::warn;
+
+use super::LocalState;
+
+/// The name of the query parameter which can carry the secret
+const TOKEN_QUERY_PARAMETER: &str = "token";
+
+/// Middleware which rejects the requests which do not carry the pre-shared secret.
+/// The secret can be either sent as a bearer token in the Authorization header or
+/// in the token query parameter.
+pub(crate) async fn require_secret(
+    State(state): State<&'static LocalState>,
+    Query(query): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+    request: Request,
+    next: Next,
+) -> Response {
+    // No secret means that everyone is welcomed
+    let secret = match &state.secret {
+        Some(secret) => secret,
+        None => return next.run(request).await,
+    };
+    // Look for the token in the header at first and then in the query
+    let token = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .or_else(|| query.get(TOKEN_QUERY_PARAMETER).map(|token| token.as_str()));
+    match token {
+        Some(token) if constant_time_eq(token.as_bytes(), secret.as_bytes()) => {
+            next.run(request).await
+        }
+        _ => {
+            warn!("Rejected unauthorized request to {}", request.uri().path());
+            StatusCode::UNAUTHORIZED.into_response()
+        }
+    }
+}
+
+/// Compares two byte slices without bailing out on the first mismatch.
+/// This way, the time which the comparison takes does not leak the secret.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
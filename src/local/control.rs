@@ -4,18 +4,26 @@ use parking_lot::Mutex;
 
 use std::ops::DerefMut;
 
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, Notify};
 
 use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::State;
 use axum::response::IntoResponse;
-use uuid::Uuid;
 
 use futures::stream::{SplitSink, StreamExt};
 
+use crate::admin;
+use crate::config::tuning;
+use crate::metrics;
+use crate::request::{ConnectionRequest, DialReport};
+use crate::shutdown;
+
+use super::{going_away, LocalState};
+
 /// The possible commands that we can be sent to the controller.
 pub(crate) enum ControllerCommand {
-    /// Request for a new connection with a specific UUID
-    NewConnection(Uuid),
+    /// Request for a new connection with a specific UUID and service
+    NewConnection(ConnectionRequest),
 }
 
 /// How many messages can be queued in the CONTROLLER_COMMANDER
@@ -25,11 +33,18 @@ const CONTROLLER_COMMANDER_CHAN_LENGTH: usize = 10;
 pub(crate) static CONTROLLER_COMMANDER: Mutex<Option<mpsc::Sender<ControllerCommand>>> =
     Mutex::new(Option::None);
 
+/// Wakes up the connections which are held until a controller is attached
+static CONTROLLER_ATTACHED: Notify = Notify::const_new();
+
 /// Entry point of websockets which are coming to control type
-pub(crate) async fn ws_handler(ws: WebSocketUpgrade) -> impl IntoResponse {
+pub(crate) async fn ws_handler(
+    ws: WebSocketUpgrade,
+    State(state): State<&'static LocalState>,
+) -> impl IntoResponse {
     // We only allow on instance of the controller.
+    // A closed channel belongs to a controller which is dead or never finished its upgrade, so it's replaced.
     let mut commander = CONTROLLER_COMMANDER.lock();
-    if commander.is_some() {
+    if commander.as_ref().is_some_and(|channel| !channel.is_closed()) {
         drop(commander);
         warn!("Duplicate controller");
         // Well, no. LOL
@@ -40,21 +55,65 @@ pub(crate) async fn ws_handler(ws: WebSocketUpgrade) -> impl IntoResponse {
     }
     // Create the channel
     let (command_sender, command_receiver) = mpsc::channel(CONTROLLER_COMMANDER_CHAN_LENGTH);
-    *commander.deref_mut() = Some(command_sender);
+    *commander.deref_mut() = Some(command_sender.clone());
     drop(commander);
+    CONTROLLER_ATTACHED.notify_waiters();
     // Finalize the upgrade process by returning upgrade callback.
     info!("Detected a new commander");
-    ws.on_upgrade(move |socket| async {
-        handle_socket(socket, command_receiver).await;
-        CONTROLLER_COMMANDER.lock().take(); // empty the commander
+    metrics::CONTROLLER_CONNECTIONS.inc();
+    ws.on_upgrade(move |socket| async move {
+        admin::set_controller_connected(true);
+        handle_socket(socket, command_receiver, state).await;
+        // Empty the commander, unless a new controller has already replaced us
+        let mut commander = CONTROLLER_COMMANDER.lock();
+        if commander.as_ref().is_some_and(|channel| channel.same_channel(&command_sender)) {
+            commander.take();
+            admin::set_controller_connected(false);
+        }
+        drop(commander);
         warn!("Commander died");
     })
 }
 
+/// Asks the controller to open a websocket for the request.
+/// The request is given back if no controller is attached.
+pub(crate) async fn send_request(request: ConnectionRequest) -> Result<(), ConnectionRequest> {
+    let channel = CONTROLLER_COMMANDER.lock().clone();
+    match channel {
+        Some(channel) => channel
+            .send(ControllerCommand::NewConnection(request))
+            .await
+            .map_err(|err| match err.0 {
+                ControllerCommand::NewConnection(request) => request,
+            }),
+        None => Err(request),
+    }
+}
+
+/// Resolves once a working controller is attached
+pub(crate) async fn controller_attached() {
+    loop {
+        let attached = CONTROLLER_ATTACHED.notified();
+        tokio::pin!(attached);
+        // Register before checking, so we do not miss a controller which attaches right now
+        attached.as_mut().enable();
+        // A dead controller might still be in the slot for a moment
+        let alive = CONTROLLER_COMMANDER
+            .lock()
+            .as_ref()
+            .is_some_and(|channel| !channel.is_closed());
+        if alive {
+            return;
+        }
+        attached.await;
+    }
+}
+
 /// Handle the connection of the controller
 async fn handle_socket(
     mut socket: WebSocket,
     mut command_receiver: mpsc::Receiver<ControllerCommand>,
+    state: &'static LocalState,
 ) {
     // At first send an ack
     if let Err(err) = socket.send(Message::Text("ack".to_owned())).await {
@@ -65,19 +124,56 @@ async fn handle_socket(
     // In that case, we can catch the errors. Note that I could have possibly just put it in the
     // select loop but I think this is quite nicer because the data will be continuously pulled.
     // Plus, I don't now if receiver.next() is cancel safe or not.
+    let _websocket = shutdown::WEBSOCKETS.track();
     let (mut sender, mut receiver) = socket.split();
     let mut recv_packet = tokio::spawn(async move {
-        // Ignore all messages except the close message
-        while let Some(Ok(Message::Close(close_code))) = receiver.next().await {
-            warn!("Controller died: {:?}", close_code);
-            return;
+        // Ignore all messages except the close message, the shutdown notice and the dial reports.
+        // The remote server pings us as well, so if nothing arrives for a while the link is dead.
+        loop {
+            let msg = match tokio::time::timeout(tuning().heartbeat_timeout, receiver.next()).await {
+                Ok(Some(Ok(msg))) => msg,
+                Ok(_) => return,
+                Err(_) => {
+                    warn!(
+                        "Controller did not respond in {}s",
+                        tuning().heartbeat_timeout.as_secs()
+                    );
+                    return;
+                }
+            };
+            match msg {
+                Message::Close(close_code) => {
+                    warn!("Controller died: {:?}", close_code);
+                    return;
+                }
+                Message::Text(text) if text == shutdown::SHUTDOWN_MESSAGE => {
+                    // Stop sending new connections to it
+                    info!("Remote server is shutting down");
+                    return;
+                }
+                // The remote server is not going to open the websocket of this connection
+                Message::Text(text) => {
+                    if let Ok(report) = DialReport::decode(&text) {
+                        report_dial(report, state);
+                    }
+                }
+                _ => {}
+            }
         }
     });
     // In a loop, wait for events
+    let mut told_peer = false;
+    let mut heartbeat = tokio::time::interval(tuning().heartbeat_interval);
     loop {
         tokio::select! {
             // If the recv_packet is done, we can simply bail
-            _ = (&mut recv_packet) => return,
+            _ = (&mut recv_packet) => break,
+            // Ping the controller so both sides know the link is alive
+            _ = heartbeat.tick() => {
+                if sender.send(Message::Ping(Vec::new())).await.is_err() {
+                    break;
+                }
+            }
             // But also check for commands
             command = command_receiver.recv() => {
                 match command {
@@ -85,19 +181,39 @@ async fn handle_socket(
                     None => unreachable!("command receiver closed"),
                 }
             }
+            // Tell the remote server that no more connections are coming
+            _ = shutdown::draining(), if !told_peer => {
+                told_peer = true;
+                let _ = sender.send(Message::Text(shutdown::SHUTDOWN_MESSAGE.to_owned())).await;
+            }
+            _ = shutdown::closing() => {
+                let _ = sender.send(going_away()).await;
+                recv_packet.abort();
+                return;
+            }
         }
     }
-    // At last, wait for their one to finish
+    recv_packet.abort();
+    let _ = sender.send(Message::Close(None)).await;
 }
 
 /// handle_control_command will handle a command
 async fn handle_control_command(command: ControllerCommand, sender: &mut SplitSink<WebSocket, Message>) {
     match command {
-        ControllerCommand::NewConnection(uuid) => {
-            // Just send the uuid in the socket
+        ControllerCommand::NewConnection(request) => {
+            // Just send the request in the socket
             // TODO: what i should do with the result
-            trace!("Asking for new connection: {uuid}");
-            let _ = sender.send(Message::Text(uuid.to_string())).await;
+            trace!("Asking for new connection: {} of {}", request.id, request.service);
+            let _ = sender.send(Message::Text(request.encode())).await;
         },
     }
-}
\ No newline at end of file
+}
+/// Answers a pending connection which the remote server has refused to open
+fn report_dial(report: DialReport, state: &LocalState) {
+    let pipe = state.pending_sockets.lock().remove(&report.id);
+    if let Some(pipe) = pipe {
+        warn!("Remote server did not open connection {}: {:?}", report.id, report.status);
+        // Dropping the pipe closes the connection once its client is answered
+        let _ = pipe.dial_result.send(report.status);
+    }
+}
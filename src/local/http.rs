@@ -0,0 +1,122 @@
+use std::io;
+
+use log::debug;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::admin::Connection;
+use crate::request::{ConnectionRequest, DialStatus, Protocol};
+use crate::tcp;
+
+use super::socket;
+use super::LocalState;
+
+/// How big the request header of a client can be
+const MAX_HEADER_SIZE: usize = 8 * 1024;
+
+const RESPONSE_ESTABLISHED: &[u8] = b"HTTP/1.1 200 Connection established\r\n\r\n";
+const RESPONSE_BAD_REQUEST: &[u8] = b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n";
+const RESPONSE_FORBIDDEN: &[u8] = b"HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\n\r\n";
+const RESPONSE_METHOD_NOT_ALLOWED: &[u8] =
+    b"HTTP/1.1 405 Method Not Allowed\r\nAllow: CONNECT\r\nContent-Length: 0\r\n\r\n";
+const RESPONSE_BAD_GATEWAY: &[u8] = b"HTTP/1.1 502 Bad Gateway\r\nContent-Length: 0\r\n\r\n";
+
+/// Reads the CONNECT request of an HTTP proxy client and proxies it if the remote server dials the target
+pub(crate) async fn handle_connect_client(
+    mut socket: TcpStream,
+    connection: &Connection,
+    service: String,
+    state: &'static LocalState,
+) -> io::Result<()> {
+    // Read the whole header. The client might send some data right after it.
+    let mut buffer = Vec::new();
+    let header = async {
+        loop {
+            if let Some(index) = buffer.windows(4).position(|window| window == b"\r\n\r\n") {
+                return Ok(index);
+            }
+            if buffer.len() > MAX_HEADER_SIZE {
+                socket.write_all(RESPONSE_BAD_REQUEST).await?;
+                return Err(invalid_data("request header is too big"));
+            }
+            let mut chunk = [0u8; 1024];
+            let n = socket.read(&mut chunk).await?;
+            if n == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            buffer.extend_from_slice(&chunk[..n]);
+        }
+    };
+    let header_end = match tokio::time::timeout(socket::HANDSHAKE_TIMEOUT, header).await {
+        Ok(header_end) => header_end?,
+        Err(_) => return Err(timed_out("request header did not arrive in time")),
+    };
+    let early_data = buffer.split_off(header_end + 4);
+    // We only care about the request line which looks like CONNECT host:port HTTP/1.1
+    let header = String::from_utf8_lossy(&buffer);
+    let mut request_line = header.lines().next().unwrap_or_default().split_whitespace();
+    let (method, target) = match (request_line.next(), request_line.next()) {
+        (Some(method), Some(target)) => (method, target),
+        _ => {
+            socket.write_all(RESPONSE_BAD_REQUEST).await?;
+            return Err(invalid_data("malformed request line"));
+        }
+    };
+    if !method.eq_ignore_ascii_case("CONNECT") {
+        socket.write_all(RESPONSE_METHOD_NOT_ALLOWED).await?;
+        return Err(invalid_data("only CONNECT is supported"));
+    }
+    let has_port = target
+        .rsplit_once(':')
+        .is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok());
+    if !has_port {
+        socket.write_all(RESPONSE_BAD_REQUEST).await?;
+        return Err(invalid_data("target must be host:port"));
+    }
+    // Ask the remote server to dial the target
+    let socket_id = connection.id;
+    debug!("HTTP connection {socket_id} requested {target}");
+    connection.set_target(target.to_owned());
+    let request = ConnectionRequest {
+        id: socket_id,
+        service,
+        protocol: Protocol::Tcp,
+        destination: Some(target.to_owned()),
+        client_address: connection.client_address,
+        listener_address: socket.local_addr().ok(),
+    };
+    let pipe = match socket::open_connection(request, state).await {
+        Some(pipe) => pipe,
+        None => return socket.write_all(RESPONSE_BAD_GATEWAY).await,
+    };
+    // Report the result of the dial to the client
+    let status = pipe.dial_result.await.unwrap_or(DialStatus::Failed);
+    let response = match status {
+        DialStatus::Connected => RESPONSE_ESTABLISHED,
+        DialStatus::Denied => RESPONSE_FORBIDDEN,
+        DialStatus::Failed => RESPONSE_BAD_GATEWAY,
+    };
+    socket.write_all(response).await?;
+    if status != DialStatus::Connected {
+        return Ok(());
+    }
+    if !early_data.is_empty() && pipe.socket_sender.send(early_data).await.is_err() {
+        return Ok(()); // websocket closed
+    }
+    tcp::proxy_tcp(
+        socket,
+        connection,
+        pipe.socket_sender,
+        pipe.websocket_receiver,
+    )
+    .await;
+    Ok(())
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn timed_out(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, message)
+}
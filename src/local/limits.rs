@@ -0,0 +1,69 @@
+//! Caps on how many connections the local server proxies at once.
+//!
+//! Each accepted client takes a permit before anything else is done for it.
+//! If there is no permit left, the client is rejected right away.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+use parking_lot::Mutex;
+
+use crate::metrics;
+
+/// The connection caps of the local server. 0 means unlimited.
+pub(crate) struct ConnectionLimits {
+    max_connections: usize,
+    max_connections_per_ip: usize,
+    /// How many connections are open in total and from each IP
+    active: Mutex<(usize, HashMap<IpAddr, usize>)>,
+}
+
+impl ConnectionLimits {
+    pub fn new(max_connections: usize, max_connections_per_ip: usize) -> ConnectionLimits {
+        ConnectionLimits {
+            max_connections,
+            max_connections_per_ip,
+            active: Mutex::default(),
+        }
+    }
+
+    /// Takes a permit for a new connection from the given IP.
+    /// Returns the reason of the rejection if a cap is reached.
+    pub fn try_acquire(&'static self, ip: IpAddr) -> Result<ConnectionPermit, &'static str> {
+        let mut active = self.active.lock();
+        let (total, per_ip) = &mut *active;
+        if self.max_connections != 0 && *total >= self.max_connections {
+            metrics::REJECTED_CONNECTIONS.inc();
+            return Err("too many connections");
+        }
+        let from_ip = per_ip.entry(ip).or_default();
+        if self.max_connections_per_ip != 0 && *from_ip >= self.max_connections_per_ip {
+            metrics::REJECTED_CONNECTIONS.inc();
+            return Err("too many connections from this IP");
+        }
+        *from_ip += 1;
+        *total += 1;
+        Ok(ConnectionPermit { limits: self, ip })
+    }
+}
+
+/// Keeps a connection counted until it's dropped
+pub(crate) struct ConnectionPermit {
+    limits: &'static ConnectionLimits,
+    ip: IpAddr,
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        let mut active = self.limits.active.lock();
+        let (total, per_ip) = &mut *active;
+        *total -= 1;
+        // Do not keep the IPs which have no connections
+        if let Some(from_ip) = per_ip.get_mut(&self.ip) {
+            *from_ip -= 1;
+            if *from_ip == 0 {
+                per_ip.remove(&self.ip);
+            }
+        }
+    }
+}
@@ -1,31 +1,250 @@
-use axum::{routing::get, Router};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::extract::ws::{close_code, CloseFrame, Message};
+use axum::{middleware, routing::get, Router};
+use futures::future::join_all;
 use log::info;
-use proxy::PendingSocketConnections;
+use parking_lot::Mutex;
+use access::{AccessRules, IpRange};
+use limits::ConnectionLimits;
+use proxy::{IdleWebsockets, PendingSocketConnections};
+
+use crate::admin;
+use crate::config::LocalConfig;
+use crate::metrics;
+use crate::mux::MuxSession;
+use crate::ratelimit;
 
+pub(crate) mod access;
+mod auth;
 mod control;
+mod http;
+mod limits;
+mod mux;
 mod proxy;
 mod socket;
+mod socks;
+pub(crate) mod tls;
+mod udp;
+
+/// The state which is shared between all handlers of the local server
+pub struct LocalState {
+    /// The sockets which are waiting for the remote server to open their websocket
+    pub pending_sockets: PendingSocketConnections,
+    /// How long the pending sockets can wait for their websocket
+    pub join_timeout: Duration,
+    /// How long the new connections can wait for a controller
+    pub hold_timeout: Duration,
+    /// How many connections can wait for a controller at once
+    pub hold_queue_length: usize,
+    /// The connections which are waiting for a controller right now
+    pub held_connections: AtomicUsize,
+    /// How many clients can be proxied at once
+    pub connection_limits: ConnectionLimits,
+    /// Should the clients of the TCP listeners start with a PROXY protocol header?
+    pub accept_proxy_protocol: bool,
+    /// The load balancers which can send the PROXY protocol header
+    pub proxy_protocol_sources: Vec<IpRange>,
+    /// Who can connect to the listeners
+    pub client_access: AccessRules,
+    /// Who can open the websockets and which proxies tell us who they are
+    pub websocket_access: AccessRules,
+    pub trusted_proxies: Vec<IpRange>,
+    /// The websockets which the remote server has opened ahead of time
+    pub idle_websockets: IdleWebsockets,
+    /// If set, every websocket must present this secret before being upgraded
+    pub secret: Option<String>,
+    /// Multiplexed websockets which the remote server has opened
+    pub mux_sessions: Mutex<Vec<Arc<MuxSession>>>,
+}
 
-pub async fn start_local_server(cf_listen_address: &str, local_listen_address: &str) {
+/// The close frame which is sent in the websockets when we are shutting down
+pub(crate) fn going_away() -> Message {
+    Message::Close(Some(CloseFrame {
+        code: close_code::AWAY,
+        reason: "shutting down".into(),
+    }))
+}
+
+/// Runs the local server. Only returns if it cannot start with our config.
+pub async fn start_local_server(config: LocalConfig) -> Result<(), String> {
+    let LocalConfig {
+        cloudflare_listen_address: cf_listen_address,
+        tcp_listen_addresses: local_listen_addresses,
+        udp_listen_addresses,
+        socks5_listen_addresses,
+        http_connect,
+        accept_proxy_protocol,
+        proxy_protocol_sources,
+        udp_idle_timeout,
+        join_timeout,
+        hold_timeout,
+        hold_queue_length,
+        max_connections,
+        max_connections_per_ip,
+        client_access,
+        websocket_access,
+        trusted_proxies,
+        secret,
+        base_path,
+        tls,
+        metrics_listen_address,
+        admin_listen_address,
+    } = config;
+    // The sockets of the local server read the uploads of the clients
+    ratelimit::init(true);
     // Create shared states.
     // We can simply leak these values to do not pay for reference counting because we need them for the rest of the program.
-    let pending_sockets: &'static PendingSocketConnections =
-        Box::leak(Box::new(PendingSocketConnections::default()));
+    let state: &'static LocalState = Box::leak(Box::new(LocalState {
+        pending_sockets: PendingSocketConnections::default(),
+        join_timeout,
+        hold_timeout,
+        hold_queue_length,
+        held_connections: AtomicUsize::new(0),
+        connection_limits: ConnectionLimits::new(max_connections, max_connections_per_ip),
+        accept_proxy_protocol,
+        proxy_protocol_sources,
+        client_access,
+        websocket_access,
+        trusted_proxies,
+        idle_websockets: IdleWebsockets::default(),
+        secret,
+        mux_sessions: Mutex::new(Vec::new()),
+    }));
 
     // Build our application with a route
-    let app = Router::new()
+    let routes = Router::new()
         .route("/control", get(control::ws_handler))
         .route("/connect", get(proxy::ws_handler))
-        .with_state(pending_sockets);
+        .route("/mux", get(mux::ws_handler))
+        .route_layer(middleware::from_fn_with_state(state, auth::require_secret))
+        // Check the address of the peer before anything else
+        .route_layer(middleware::from_fn_with_state(state, access::require_allowed_peer));
+    // Put them under the base path if needed
+    let app = match base_path {
+        Some(base_path) => {
+            info!("Serving websockets under {base_path}");
+            Router::new().nest(&base_path, routes)
+        }
+        None => routes,
+    }
+    .with_state(state);
 
-    // Run our app with hyper on another task
+    // Bind every socket before starting anything, so a bad address stops the server right away
     info!("Cloudflare listen is {cf_listen_address}");
-    let listener = tokio::net::TcpListener::bind(cf_listen_address)
+    let listener = tokio::net::TcpListener::bind(&cf_listen_address)
         .await
-        .expect("cannot bind the Axum socket");
-    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        .map_err(|err| format!("Cannot bind the Cloudflare socket {cf_listen_address}: {err}"))?;
+    let tls = match tls {
+        Some(files) => Some((tls::load(&files)?, files)),
+        None => None,
+    };
+    let metrics_listener = match &metrics_listen_address {
+        Some(address) => Some(metrics::bind(address).await?),
+        None => None,
+    };
+    let admin_listener = match &admin_listen_address {
+        Some(address) => Some(admin::bind(address).await?),
+        None => None,
+    };
+    let mut udp_sockets = Vec::with_capacity(udp_listen_addresses.len());
+    for mapping in udp_listen_addresses {
+        udp_sockets.push((udp::bind(&mapping).await?, mapping));
+    }
+    let mut socks_listeners = Vec::with_capacity(socks5_listen_addresses.len());
+    for mapping in socks5_listen_addresses {
+        socks_listeners.push((socks::bind(&mapping).await?, mapping));
+    }
+    let mut tcp_listeners = Vec::with_capacity(local_listen_addresses.len());
+    for mapping in local_listen_addresses {
+        tcp_listeners.push((socket::bind(&mapping).await?, mapping));
+    }
+
+    // Run our app with hyper on another task
+    match tls {
+        Some((config, files)) => {
+            info!("Serving websockets over TLS");
+            tokio::spawn(tls::serve(listener, app, config, files));
+        }
+        None => {
+            tokio::spawn(async move {
+                // The peer address is needed by the access rules
+                let app = app.into_make_service_with_connect_info::<SocketAddr>();
+                axum::serve(listener, app).await.unwrap()
+            });
+        }
+    }
+
+    // The metrics are served on their own address
+    if let Some(listener) = metrics_listener {
+        tokio::spawn(metrics::serve(listener, move |out| write_metrics(state, out)));
+    }
+    // So is the admin API
+    if let Some(listener) = admin_listener {
+        tokio::spawn(admin::serve(listener));
+    }
+
+    // Listen for UDP datagrams of every UDP mapping in other tasks
+    for (udp_socket, mapping) in udp_sockets {
+        tokio::spawn(udp::handle_udp_socket(udp_socket, mapping, state, udp_idle_timeout));
+    }
+
+    // SOCKS5 clients are also accepted in other tasks
+    for (listener, mapping) in socks_listeners {
+        tokio::spawn(socks::handle_socks_socket(listener, mapping, state));
+    }
+
+    // In main thread, wait for TCP sockets of every mapping
+    join_all(
+        tcp_listeners
+            .into_iter()
+            .map(|(listener, mapping)| socket::handle_socket(listener, mapping, state, http_connect)),
+    )
+    .await;
+    // Only UDP and SOCKS5 listeners are left
+    std::future::pending::<()>().await;
+    Ok(())
+}
 
-    // In main thread, wait for TCP sockets
-    info!("Local listen is {local_listen_address}");
-    socket::handle_socket(&local_listen_address, pending_sockets).await;
+/// Writes the metrics which only the local server has
+fn write_metrics(state: &LocalState, out: &mut String) {
+    metrics::write_gauge(
+        out,
+        "rwp_pending_connections",
+        "Connections which are waiting for the remote server to open their websocket",
+        state.pending_sockets.lock().len(),
+    );
+    metrics::write_gauge(
+        out,
+        "rwp_held_connections",
+        "Connections which are waiting for the remote server to attach its controller",
+        state.held_connections.load(Ordering::Relaxed),
+    );
+    metrics::write_gauge(
+        out,
+        "rwp_idle_websockets",
+        "Websockets which the remote server has opened ahead of time",
+        state.idle_websockets.lock().len(),
+    );
+    metrics::write_gauge(
+        out,
+        "rwp_mux_sessions",
+        "Multiplexed websockets which the remote server has opened",
+        state.mux_sessions.lock().len(),
+    );
+    metrics::write_counter(
+        out,
+        "rwp_join_timeouts_total",
+        "Connections which were closed because the remote server did not open their websocket in time",
+        &metrics::JOIN_TIMEOUTS,
+    );
+    metrics::write_counter(
+        out,
+        "rwp_rejected_connections_total",
+        "Clients which were rejected because of max_connections or max_connections_per_ip",
+        &metrics::REJECTED_CONNECTIONS,
+    );
 }
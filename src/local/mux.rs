@@ -0,0 +1,93 @@
+use std::sync::Arc;
+
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::State;
+use axum::response::IntoResponse;
+use futures::{SinkExt, StreamExt};
+use log::{info, warn};
+
+use crate::mux::{self, MuxSession};
+use crate::shutdown;
+
+use super::{going_away, LocalState};
+
+/// Entry point of websockets which multiplex the connections
+pub(crate) async fn ws_handler(
+    ws: WebSocketUpgrade,
+    State(state): State<&'static LocalState>,
+) -> impl IntoResponse {
+    ws.on_upgrade(move |socket| handle_socket(socket, state))
+}
+
+async fn handle_socket(socket: WebSocket, state: &LocalState) {
+    let (session, mut frames) = MuxSession::new();
+    state.mux_sessions.lock().push(session.clone());
+    info!("Mux session joined");
+    let _websocket = shutdown::WEBSOCKETS.track();
+    let (mut sender, mut receiver) = socket.split();
+    // Write the frames in the websocket until we are shutting down
+    let mut writer = tokio::spawn(async move {
+        loop {
+            let frame = tokio::select! {
+                frame = frames.recv() => match frame {
+                    Some(frame) => frame,
+                    None => return,
+                },
+                _ = shutdown::closing() => {
+                    let _ = sender.send(going_away()).await;
+                    return;
+                }
+            };
+            if let Err(err) = sender.send(Message::Binary(frame.encode())).await {
+                warn!("Cannot write in mux session: {err}");
+                return;
+            }
+        }
+    });
+    // Read the frames and dispatch them
+    let reader_session = session.clone();
+    let mut reader = tokio::spawn(async move {
+        while let Some(Ok(msg)) = receiver.next().await {
+            match msg {
+                Message::Binary(data) => {
+                    let frame = match mux::decode_or_warn(data) {
+                        Some(frame) => frame,
+                        None => continue,
+                    };
+                    if let Some(frame) = reader_session.dispatch(frame).await {
+                        warn!("Remote server sent unexpected frame: {:?}", frame);
+                    }
+                }
+                Message::Close(close_code) => {
+                    info!("Mux session closed with {:?}", close_code);
+                    return;
+                }
+                _ => {} // do nothing and poll again
+            }
+        }
+    });
+    // Wait until either of them dies
+    tokio::select! {
+        _ = (&mut writer) => {},
+        _ = (&mut reader) => {},
+    };
+    writer.abort();
+    reader.abort();
+    // Remove the session and close every stream in it
+    state
+        .mux_sessions
+        .lock()
+        .retain(|other| !Arc::ptr_eq(other, &session));
+    session.close();
+    warn!("Mux session died");
+}
+
+/// Picks the session with the least open streams, if there is any
+pub(crate) fn pick_session(state: &LocalState) -> Option<Arc<MuxSession>> {
+    state
+        .mux_sessions
+        .lock()
+        .iter()
+        .min_by_key(|session| session.stream_count())
+        .cloned()
+}
@@ -1,16 +1,23 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::str::FromStr;
 
 use axum::extract::State;
 use futures::{SinkExt, StreamExt};
-use log::{debug, info, warn};
+use log::{debug, info, trace, warn};
 use parking_lot::Mutex;
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, oneshot};
 
 use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::http::{HeaderMap, HeaderValue};
 use axum::response::IntoResponse;
 use uuid::Uuid;
 
+use crate::compression;
+use crate::request::{ConnectionRequest, DialStatus};
+use crate::shutdown;
+
+use super::{going_away, LocalState};
+
 pub type PendingSocketConnections = Mutex<HashMap<Uuid, ConnectionPipe>>;
 
 /// ConnectionPipe is used to connect a socket to a websocket.
@@ -19,21 +26,55 @@ pub struct ConnectionPipe {
     pub websocket_data: mpsc::Sender<Vec<u8>>,
     /// Websocket await this pipe to get the data from the opened socket
     pub socket_data: mpsc::Receiver<Vec<u8>>,
+    /// The dial result which the remote server reports is sent here
+    pub dial_result: oneshot::Sender<DialStatus>,
+}
+
+/// Websockets which the remote server has opened ahead of time and are waiting for a connection
+pub type IdleWebsockets = Mutex<VecDeque<IdleWebsocket>>;
+
+/// The message which the remote server sends instead of the UUID to open an idle websocket
+const POOL_GREETING: &str = "pool";
+
+/// An idle websocket which is waiting to be assigned to a connection
+pub struct IdleWebsocket {
+    /// Used to remove the websocket from the idle list if it dies
+    id: Uuid,
+    /// The connection which is assigned to the websocket is sent here
+    assign: oneshot::Sender<(ConnectionRequest, ConnectionPipe)>,
 }
 
 /// Entry point of websockets which are coming to proxy the data between a remote peer and a local peer.
 pub async fn ws_handler(
     ws: WebSocketUpgrade,
-    State(pending_connections): State<&'static PendingSocketConnections>,
+    headers: HeaderMap,
+    State(state): State<&'static LocalState>,
 ) -> impl IntoResponse {
-    ws.on_upgrade(move |socket| handle_socket(socket, pending_connections))
+    // Compress the data if the remote server asks for it and we have also enabled it
+    let header = headers.get(compression::HEADER);
+    let compressed = compression::negotiate(header.and_then(|value| value.to_str().ok()));
+    let mut response = ws
+        .on_upgrade(move |socket| handle_socket(socket, state, compressed))
+        .into_response();
+    if let (true, Some(algorithm)) = (compressed, compression::offer()) {
+        response
+            .headers_mut()
+            .insert(compression::HEADER, HeaderValue::from_static(algorithm));
+    }
+    response
 }
 
-async fn handle_socket(mut socket: WebSocket, pending_connections: &PendingSocketConnections) {
-    // The first packet must be the UUID of the connection
-    let (mut connection_pipe, socket_id) = match socket.recv().await {
-        Some(Ok(Message::Text(uuid))) => match uuid::Uuid::from_str(&uuid.trim()) {
-            Ok(uuid) => match pending_connections.lock().remove(&uuid) {
+async fn handle_socket(mut socket: WebSocket, state: &LocalState, compressed: bool) {
+    // The first packet must be the UUID of the connection or the pool greeting
+    let (connection_pipe, socket_id) = match socket.recv().await {
+        Some(Ok(Message::Text(greeting))) if greeting.trim() == POOL_GREETING => {
+            match wait_for_assignment(&mut socket, &state.idle_websockets).await {
+                Some(assignment) => assignment,
+                None => return,
+            }
+        }
+        Some(Ok(Message::Text(uuid))) => match uuid::Uuid::from_str(uuid.trim()) {
+            Ok(uuid) => match state.pending_sockets.lock().remove(&uuid) {
                 Some(pipe) => (pipe, uuid),
                 None => {
                     warn!("UUID {uuid} does not exists");
@@ -48,18 +89,120 @@ async fn handle_socket(mut socket: WebSocket, pending_connections: &PendingSocke
         _ => return, // socket closed?
     };
     debug!("Websocket of connection {socket_id} joined");
+    proxy_websocket(socket, connection_pipe, socket_id, compressed).await;
+}
+
+/// Parks an idle websocket until a connection is assigned to it.
+/// On assignment, the UUID of the connection is sent to the remote server.
+async fn wait_for_assignment(
+    socket: &mut WebSocket,
+    idle_websockets: &IdleWebsockets,
+) -> Option<(ConnectionPipe, Uuid)> {
+    let _websocket = shutdown::WEBSOCKETS.track();
+    let id = Uuid::new_v4();
+    let (assign, mut assignment) = oneshot::channel();
+    idle_websockets.lock().push_back(IdleWebsocket { id, assign });
+    trace!("Idle websocket {id} joined the pool");
+    loop {
+        tokio::select! {
+            result = &mut assignment => {
+                let (request, connection_pipe) = result.ok()?;
+                if let Err(err) = socket.send(Message::Text(request.encode())).await {
+                    warn!("Cannot assign connection {} to idle websocket: {err}", request.id);
+                    return None;
+                }
+                return Some((connection_pipe, request.id));
+            }
+            msg = socket.recv() => match msg {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => {
+                    // The remote server closed the websocket, so remove it from the pool
+                    idle_websockets.lock().retain(|idle| idle.id != id);
+                    trace!("Idle websocket {id} left the pool");
+                    return None;
+                }
+                _ => {} // pings and such, poll again
+            },
+            // No more connections are coming, so the idle websockets are useless
+            _ = shutdown::draining() => {
+                idle_websockets.lock().retain(|idle| idle.id != id);
+                let _ = socket.send(going_away()).await;
+                return None;
+            }
+        }
+    }
+}
+
+/// Tries to assign a connection to one of the idle websockets.
+/// If there is no idle websocket, the pipe is given back.
+pub(crate) fn assign_idle_websocket(
+    idle_websockets: &IdleWebsockets,
+    request: ConnectionRequest,
+    mut connection_pipe: ConnectionPipe,
+) -> Result<(), ConnectionPipe> {
+    let mut request = request;
+    loop {
+        let idle = match idle_websockets.lock().pop_front() {
+            Some(idle) => idle,
+            None => return Err(connection_pipe),
+        };
+        // The websocket might have died in the meantime, so try the next one
+        match idle.assign.send((request, connection_pipe)) {
+            Ok(()) => return Ok(()),
+            Err((returned_request, pipe)) => {
+                request = returned_request;
+                connection_pipe = pipe;
+            }
+        }
+    }
+}
+
+/// Proxies the data between a joined websocket and the pipe of its connection
+async fn proxy_websocket(
+    socket: WebSocket,
+    connection_pipe: ConnectionPipe,
+    socket_id: Uuid,
+    compressed: bool,
+) {
     // Now we simply proxy the data
+    let _websocket = shutdown::WEBSOCKETS.track();
     let (mut sender, mut receiver) = socket.split();
+    let ConnectionPipe {
+        websocket_data,
+        mut socket_data,
+        dial_result,
+    } = connection_pipe;
     // Create another task for watch for the incoming data from the websocket.
     // In that case, we can catch the errors. Note that I could have possibly just put it in the
     // select loop but I think this is quite nicer because the data will be continuously pulled.
     // Plus, I don't now if receiver.next() is cancel safe or not.
     let mut recv_packet = tokio::spawn(async move {
+        let mut dial_result = Some(dial_result);
         while let Some(Ok(msg)) = receiver.next().await {
             match msg {
                 Message::Binary(payload) => {
-                    connection_pipe.websocket_data.send(payload).await.unwrap();
+                    let payload = match compressed {
+                        true => match compression::decompress(payload) {
+                            Ok(payload) => payload,
+                            Err(err) => {
+                                warn!("Invalid compressed data in websocket {socket_id}: {err}");
+                                return;
+                            }
+                        },
+                        false => payload,
+                    };
+                    if websocket_data.send(payload).await.is_err() {
+                        return; // socket closed
+                    }
                 }
+                Message::Text(status) => match DialStatus::decode(&status) {
+                    // The dial result is reported only once
+                    Ok(status) => {
+                        if let Some(dial_result) = dial_result.take() {
+                            let _ = dial_result.send(status);
+                        }
+                    }
+                    Err(err) => warn!("Invalid dial status of {socket_id}: {err}"),
+                },
                 Message::Close(close_code) => {
                     info!("Websocket {socket_id} closed with {:?}", close_code);
                     return;
@@ -74,15 +217,30 @@ async fn handle_socket(mut socket: WebSocket, pending_connections: &PendingSocke
             // If the recv_packet is done, we can simply bail
             _ = (&mut recv_packet) => return,
             // But also check for data to send
-            data = connection_pipe.socket_data.recv() => {
+            data = socket_data.recv() => {
                 match data {
-                    Some(data) => sender.send(Message::Binary(data)).await.unwrap(), // TODO: better error handling
+                    Some(data) => {
+                        let data = if compressed {
+                            compression::compress(data)
+                        } else {
+                            data
+                        };
+                        if let Err(err) = sender.send(Message::Binary(data)).await {
+                            debug!("Websocket {socket_id} returned error: {err}");
+                            break;
+                        }
+                    }
                     None => { // connection closed
-                        recv_packet.abort();
-                        return;
+                        let _ = sender.send(Message::Close(None)).await;
+                        break;
                     },
                 }
             }
+            _ = shutdown::closing() => {
+                let _ = sender.send(going_away()).await;
+                break;
+            }
         }
     }
+    recv_packet.abort();
 }
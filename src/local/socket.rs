@@ -1,104 +1,258 @@
-use log::{debug, warn};
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use log::{debug, info, trace, warn};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpStream,
+    net::{TcpListener, TcpStream},
     sync::mpsc::{self, Receiver, Sender},
+    sync::oneshot,
+    time::Instant,
 };
 use uuid::Uuid;
 
-use crate::local::{control, proxy::ConnectionPipe};
+use crate::admin;
+use crate::arguments::Mapping;
+use crate::config::tuning;
+use crate::local::{access, control, http, mux, proxy, proxy::ConnectionPipe};
+use crate::metrics;
+use crate::proxy_protocol;
+use crate::request::{ConnectionRequest, DialStatus, Protocol};
+use crate::shutdown;
+use crate::tcp;
 
-use super::proxy;
+use super::LocalState;
 
-/// How many packets can be queued in the socket queue
-const SOCKET_QUEUE_LENGTH: usize = 32;
-/// How big is our read buffer size
-const READ_BUFFER_SIZE: usize = 32 * 1024;
+/// How long a client can take to send its PROXY protocol header
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long an HTTP CONNECT or SOCKS5 client can take to send its request.
+/// The client already holds a slot of the connection caps, so it can't wait forever.
+pub(crate) const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
 
-/// This function will handle the socket listening and controlling the controller
-/// to open new connections and such
-pub(crate) async fn handle_socket(listen: &str, pending_packets: &proxy::PendingSocketConnections) {
-    // Create the socket and listen
-    let listener = tokio::net::TcpListener::bind(listen)
+/// Binds the socket of a TCP listener
+pub(crate) async fn bind(mapping: &Mapping) -> Result<TcpListener, String> {
+    TcpListener::bind(&mapping.address)
         .await
-        .expect("cannot bind the TCP socket");
+        .map_err(|err| format!("Cannot bind the TCP socket {}: {err}", mapping.address))
+}
+
+/// This function will handle the socket listening and controlling the controller
+/// to open new connections and such.
+/// In HTTP CONNECT mode, the clients choose the target which the remote server dials.
+pub(crate) async fn handle_socket(
+    listener: TcpListener,
+    mapping: Mapping,
+    state: &'static LocalState,
+    http_connect: bool,
+) {
+    info!("Listening on {} for service {}", mapping.address, mapping.name);
     loop {
-        let (socket, socket_address) = listener.accept().await.expect("cannot accept connections");
-        // For each socket, create a new UUID
-        let socket_id = Uuid::new_v4();
-        debug!("Accepted connection {socket_address} associated with {socket_id}");
-        // Create the pipes and add the request in the pending sockets
-        let (socket_sender, socket_receiver) = mpsc::channel(SOCKET_QUEUE_LENGTH);
-        let (websocket_sender, websocket_receiver) = mpsc::channel(SOCKET_QUEUE_LENGTH);
-        let connection_pipe = ConnectionPipe {
-            websocket_data: websocket_sender,
-            socket_data: socket_receiver,
-        };
-        pending_packets.lock().insert(socket_id, connection_pipe);
-        // Send the request to the server before Cloudflare
-        let control_channel = control::CONTROLLER_COMMANDER
-            .lock()
-            .as_ref()
-            .map(|c| c.clone());
-        match control_channel {
-            Some(channel) => channel
-                .send(control::ControllerCommand::NewConnection(socket_id))
-                .await
-                .unwrap(),
-            None => {
-                // The server's controller is not established yet
-                warn!("Control websocket not established yet...");
-                pending_packets.lock().remove(&socket_id);
-                continue;
+        let (socket, socket_address) = tokio::select! {
+            accepted = listener.accept() => accepted.expect("cannot accept connections"),
+            _ = shutdown::draining() => {
+                info!("Stopped listening on {}", mapping.address);
+                return;
             }
         };
-        // Wait for acceptance
-        tokio::task::spawn(handle_opened_socket(
-            socket,
-            socket_id,
-            socket_sender,
-            websocket_receiver,
-        ));
+        // The connection is counted until it's finished, so we can wait for it when shutting down
+        let tracked = shutdown::CONNECTIONS.track();
+        // Reading the PROXY header or opening the connection might take a while, so do not block the listener
+        let service = mapping.name.clone();
+        tokio::task::spawn(async move {
+            handle_client(socket, socket_address, service, state, http_connect).await;
+            drop(tracked);
+        });
     }
 }
 
-async fn handle_opened_socket(
-    socket: TcpStream,
-    socket_id: Uuid,
-    socket_sender: Sender<Vec<u8>>,
-    mut websocket_receiver: Receiver<Vec<u8>>,
+/// Checks a client which is accepted on a TCP listener and proxies it if it's allowed
+async fn handle_client(
+    mut socket: TcpStream,
+    mut socket_address: SocketAddr,
+    service: String,
+    state: &'static LocalState,
+    http_connect: bool,
 ) {
-    // We dont need to wait for the websocket, just send the data in the pipes and hope for the best.
-    let (mut socket_r, mut socket_w) = socket.into_split();
-    // First spawn a task that only reads the data from the socket
-    let mut socket_reader_task = tokio::task::spawn(async move {
-        let mut read_buffer = [0u8; READ_BUFFER_SIZE];
-        while let Ok(n) = socket_r.read(&mut read_buffer).await {
-            socket_sender
-                .send(read_buffer[..n].to_owned())
-                .await
-                .unwrap();
+    let mut listener_address = socket.local_addr().ok();
+    // Behind a load balancer, the address of the client is in the PROXY header.
+    // Only the load balancers can send it, the others are treated like any other client
+    // so they can't make us wait for a header or pretend to be someone else.
+    if state.accept_proxy_protocol && access::is_trusted(&state.proxy_protocol_sources, socket_address.ip()) {
+        let header = tokio::time::timeout(PROXY_HEADER_TIMEOUT, proxy_protocol::read_header(&mut socket)).await;
+        match header {
+            Ok(Ok(Some((source, destination)))) => {
+                socket_address = source;
+                listener_address = Some(destination);
+            }
+            Ok(Ok(None)) => {} // the load balancer itself, like a health check
+            Ok(Err(err)) => {
+                warn!("Rejected connection {socket_address} of {service}: {err}");
+                return;
+            }
+            Err(_) => {
+                warn!("Rejected connection {socket_address} of {service}: no PROXY header in time");
+                return;
+            }
         }
-        debug!("Socket {socket_id} closed on read");
-    });
-    // Now in a loop, wait for either a received packet from websocket or reader finishing
-    loop {
-        tokio::select! {
-            // If the socket_reader_task is done, we can simply bail
-            _ = (&mut socket_reader_task) => return,
-            // But also check for commands
-            data = websocket_receiver.recv() => {
-                match data {
-                    Some(data) => { // if there is data, write it into the pipe
-                        socket_w.write(&data).await.unwrap();
-                    }
-                    None => { // websocket closed
-                        socket_reader_task.abort();
-                        debug!("Socket {socket_id} closed on write");
-                        return; // socket_w will be dropped and connection will be closed
-                    }
+    }
+    if !state.client_access.allows(socket_address.ip()) {
+        warn!("Rejected connection {socket_address} of {service}: not allowed");
+        return;
+    }
+    // Drop the client right away if there are too many connections
+    let _permit = match state.connection_limits.try_acquire(socket_address.ip()) {
+        Ok(permit) => permit,
+        Err(reason) => {
+            warn!("Rejected connection {socket_address} of {service}: {reason}");
+            return;
+        }
+    };
+    // For each socket, create a new UUID
+    let socket_id = Uuid::new_v4();
+    debug!("Accepted connection {socket_address} of {service} associated with {socket_id}");
+    let connection = admin::register(socket_id, service.clone(), Protocol::Tcp, Some(socket_address));
+    if http_connect {
+        // The target is not known until we read the request
+        let client = http::handle_connect_client(socket, &connection, service, state);
+        if let Some(Err(err)) = connection.unless_killed(client).await {
+            debug!("HTTP client {socket_address} failed: {err}");
+        }
+        return;
+    }
+    let request = ConnectionRequest {
+        id: socket_id,
+        service,
+        protocol: Protocol::Tcp,
+        destination: None,
+        client_address: Some(socket_address),
+        listener_address,
+    };
+    if let Some(pipe) = open_connection(request, state).await {
+        // We dont need to wait for the websocket, just send the data in the pipes and hope for the best.
+        let proxy = tcp::proxy_tcp(socket, &connection, pipe.socket_sender, pipe.websocket_receiver);
+        connection.unless_killed(proxy).await;
+    }
+}
+
+/// The local end of a connection which goes through the remote server
+pub(crate) struct LocalPipe {
+    /// The data sent here is written in the remote socket
+    pub socket_sender: Sender<Vec<u8>>,
+    /// The data which is read from the remote socket
+    pub websocket_receiver: Receiver<Vec<u8>>,
+    /// Resolves once the remote server has dialed the target
+    pub dial_result: oneshot::Receiver<DialStatus>,
+}
+
+/// Asks the remote server to open a connection for the request.
+/// Returns the pipes which the local socket should use to send and receive the data.
+pub(crate) async fn open_connection(
+    request: ConnectionRequest,
+    state: &'static LocalState,
+) -> Option<LocalPipe> {
+    let socket_id = request.id;
+    // Create the pipes
+    let (socket_sender, socket_receiver) = mpsc::channel(tuning().socket_queue_length);
+    let (websocket_sender, websocket_receiver) = mpsc::channel(tuning().socket_queue_length);
+    let (dial_result_sender, reported_dial_result) = oneshot::channel();
+    // Measure how long the remote server takes to report the dial before handing it over
+    let (forwarded_dial_result, dial_result) = oneshot::channel();
+    let started = Instant::now();
+    tokio::spawn(async move {
+        if let Ok(status) = reported_dial_result.await {
+            metrics::CONNECT_DURATION.observe(started.elapsed());
+            if status == DialStatus::Connected {
+                if let Some(connection) = admin::lookup(socket_id) {
+                    connection.set_joined();
                 }
             }
+            let _ = forwarded_dial_result.send(status);
+        }
+    });
+    let local_pipe = LocalPipe {
+        socket_sender,
+        websocket_receiver,
+        dial_result,
+    };
+    // If the remote server has a multiplexed websocket, simply open a stream in it
+    if let Some(session) = mux::pick_session(state) {
+        session
+            .open_stream(request, socket_receiver, websocket_sender, dial_result_sender)
+            .await;
+        return Some(local_pipe);
+    }
+    let connection_pipe = ConnectionPipe {
+        websocket_data: websocket_sender,
+        socket_data: socket_receiver,
+        dial_result: dial_result_sender,
+    };
+    // Next, try to use a websocket which the remote server has opened ahead of time
+    let connection_pipe =
+        match proxy::assign_idle_websocket(&state.idle_websockets, request.clone(), connection_pipe) {
+            Ok(()) => {
+                trace!("Assigned connection {socket_id} to an idle websocket");
+                return Some(local_pipe);
+            }
+            Err(connection_pipe) => connection_pipe,
+        };
+    // Otherwise, add the request in the pending sockets
+    let pending_packets = &state.pending_sockets;
+    pending_packets.lock().insert(socket_id, connection_pipe);
+    // Send the request to the server before Cloudflare
+    if let Err(request) = control::send_request(request).await {
+        // The server's controller is not established yet, so wait for it a bit
+        if !hold_request(request, state).await {
+            warn!("Control websocket not established yet...");
+            pending_packets.lock().remove(&socket_id);
+            return None;
         }
     }
+    // Wait for acceptance, but not forever
+    let join_timeout = state.join_timeout;
+    tokio::spawn(async move {
+        tokio::time::sleep(join_timeout).await;
+        // If it's still pending, the remote server is not going to open the websocket
+        if pending_packets.lock().remove(&socket_id).is_none() {
+            return;
+        }
+        warn!(
+            "Remote server did not open the websocket of {socket_id} in {}s",
+            join_timeout.as_secs()
+        );
+        metrics::JOIN_TIMEOUTS.inc();
+        if let Some(connection) = admin::lookup(socket_id) {
+            connection.kill();
+        }
+    });
+    Some(local_pipe)
+}
+
+/// Holds the request until a controller is attached and then sends it.
+/// Returns false if the hold queue is full or the controller does not come back in hold_timeout.
+async fn hold_request(mut request: ConnectionRequest, state: &LocalState) -> bool {
+    let socket_id = request.id;
+    if state.held_connections.fetch_add(1, Ordering::Relaxed) >= state.hold_queue_length {
+        state.held_connections.fetch_sub(1, Ordering::Relaxed);
+        return false;
+    }
+    debug!("Holding connection {socket_id} until a controller is attached");
+    let deadline = Instant::now() + state.hold_timeout;
+    let sent = loop {
+        if tokio::time::timeout_at(deadline, control::controller_attached())
+            .await
+            .is_err()
+        {
+            break false;
+        }
+        // The controller might die again before we send the request
+        request = match control::send_request(request).await {
+            Ok(()) => break true,
+            Err(request) => request,
+        };
+    };
+    state.held_connections.fetch_sub(1, Ordering::Relaxed);
+    if sent {
+        debug!("Sent the held connection {socket_id}");
+    }
+    sent
 }
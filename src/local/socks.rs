@@ -0,0 +1,195 @@
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use log::{debug, info, warn};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use uuid::Uuid;
+
+use crate::admin::{self, Connection};
+use crate::arguments::Mapping;
+use crate::request::{ConnectionRequest, DialStatus, Protocol};
+use crate::shutdown;
+use crate::tcp;
+
+use super::socket;
+use super::LocalState;
+
+const SOCKS_VERSION: u8 = 5;
+const METHOD_NO_AUTHENTICATION: u8 = 0;
+const METHOD_NOT_ACCEPTABLE: u8 = 0xFF;
+const COMMAND_CONNECT: u8 = 1;
+const ADDRESS_IPV4: u8 = 1;
+const ADDRESS_DOMAIN: u8 = 3;
+const ADDRESS_IPV6: u8 = 4;
+const REPLY_SUCCEEDED: u8 = 0;
+const REPLY_GENERAL_FAILURE: u8 = 1;
+const REPLY_NOT_ALLOWED: u8 = 2;
+const REPLY_HOST_UNREACHABLE: u8 = 4;
+const REPLY_COMMAND_NOT_SUPPORTED: u8 = 7;
+const REPLY_ADDRESS_NOT_SUPPORTED: u8 = 8;
+
+/// Binds the socket of a SOCKS5 listener
+pub(crate) async fn bind(mapping: &Mapping) -> Result<TcpListener, String> {
+    TcpListener::bind(&mapping.address)
+        .await
+        .map_err(|err| format!("Cannot bind the SOCKS5 socket {}: {err}", mapping.address))
+}
+
+/// Accepts SOCKS5 clients and asks the remote server to dial their requested destination
+pub(crate) async fn handle_socks_socket(
+    listener: TcpListener,
+    mapping: Mapping,
+    state: &'static LocalState,
+) {
+    info!(
+        "Listening on SOCKS5 {} for service {}",
+        mapping.address, mapping.name
+    );
+    loop {
+        let (socket, socket_address) = tokio::select! {
+            accepted = listener.accept() => accepted.expect("cannot accept connections"),
+            _ = shutdown::draining() => {
+                info!("Stopped listening on SOCKS5 {}", mapping.address);
+                return;
+            }
+        };
+        if !state.client_access.allows(socket_address.ip()) {
+            warn!("Rejected SOCKS5 client {socket_address}: not allowed");
+            continue;
+        }
+        let permit = match state.connection_limits.try_acquire(socket_address.ip()) {
+            Ok(permit) => permit,
+            Err(reason) => {
+                warn!("Rejected SOCKS5 client {socket_address}: {reason}");
+                continue;
+            }
+        };
+        let service = mapping.name.clone();
+        let tracked = shutdown::CONNECTIONS.track();
+        let connection = admin::register(Uuid::new_v4(), service.clone(), Protocol::Tcp, Some(socket_address));
+        tokio::task::spawn(async move {
+            let client = handle_client(socket, &connection, service, state);
+            if let Some(Err(err)) = connection.unless_killed(client).await {
+                debug!("SOCKS5 client {socket_address} failed: {err}");
+            }
+            drop((tracked, permit));
+        });
+    }
+}
+
+/// Does the SOCKS5 handshake and proxies the client if the remote server dials the destination
+async fn handle_client(
+    mut socket: TcpStream,
+    connection: &Connection,
+    service: String,
+    state: &'static LocalState,
+) -> io::Result<()> {
+    // The client holds a slot of the connection caps, so it can't take forever to send its request
+    let handshake = tokio::time::timeout(socket::HANDSHAKE_TIMEOUT, read_request(&mut socket));
+    let destination = match handshake.await {
+        Ok(destination) => destination?,
+        Err(_) => return Err(timed_out("request did not arrive in time")),
+    };
+    // Ask the remote server to dial the destination
+    let socket_id = connection.id;
+    debug!("SOCKS5 connection {socket_id} requested {destination}");
+    connection.set_target(destination.clone());
+    let request = ConnectionRequest {
+        id: socket_id,
+        service,
+        protocol: Protocol::Tcp,
+        destination: Some(destination),
+        client_address: connection.client_address,
+        listener_address: socket.local_addr().ok(),
+    };
+    let pipe = match socket::open_connection(request, state).await {
+        Some(pipe) => pipe,
+        None => return reply(&mut socket, REPLY_GENERAL_FAILURE).await,
+    };
+    // Report the result of the dial to the client
+    let status = pipe.dial_result.await.unwrap_or(DialStatus::Failed);
+    let reply_code = match status {
+        DialStatus::Connected => REPLY_SUCCEEDED,
+        DialStatus::Denied => REPLY_NOT_ALLOWED,
+        DialStatus::Failed => REPLY_HOST_UNREACHABLE,
+    };
+    reply(&mut socket, reply_code).await?;
+    if status == DialStatus::Connected {
+        tcp::proxy_tcp(
+            socket,
+            connection,
+            pipe.socket_sender,
+            pipe.websocket_receiver,
+        )
+        .await;
+    }
+    Ok(())
+}
+
+/// Does the SOCKS5 handshake until the request and returns the requested destination
+async fn read_request(socket: &mut TcpStream) -> io::Result<String> {
+    // At first the client sends the authentication methods it supports
+    let mut header = [0u8; 2];
+    socket.read_exact(&mut header).await?;
+    if header[0] != SOCKS_VERSION {
+        return Err(invalid_data("not a SOCKS5 client"));
+    }
+    let mut methods = vec![0u8; header[1] as usize];
+    socket.read_exact(&mut methods).await?;
+    if !methods.contains(&METHOD_NO_AUTHENTICATION) {
+        socket
+            .write_all(&[SOCKS_VERSION, METHOD_NOT_ACCEPTABLE])
+            .await?;
+        return Err(invalid_data("client requires authentication"));
+    }
+    socket
+        .write_all(&[SOCKS_VERSION, METHOD_NO_AUTHENTICATION])
+        .await?;
+    // Then it sends the request
+    let mut request = [0u8; 4];
+    socket.read_exact(&mut request).await?;
+    if request[1] != COMMAND_CONNECT {
+        reply(socket, REPLY_COMMAND_NOT_SUPPORTED).await?;
+        return Err(invalid_data("only CONNECT is supported"));
+    }
+    let host = match request[3] {
+        ADDRESS_IPV4 => {
+            let mut address = [0u8; 4];
+            socket.read_exact(&mut address).await?;
+            Ipv4Addr::from(address).to_string()
+        }
+        ADDRESS_DOMAIN => {
+            let length = socket.read_u8().await? as usize;
+            let mut domain = vec![0u8; length];
+            socket.read_exact(&mut domain).await?;
+            String::from_utf8(domain).map_err(|_| invalid_data("domain is not UTF-8"))?
+        }
+        ADDRESS_IPV6 => {
+            let mut address = [0u8; 16];
+            socket.read_exact(&mut address).await?;
+            format!("[{}]", Ipv6Addr::from(address))
+        }
+        _ => {
+            reply(socket, REPLY_ADDRESS_NOT_SUPPORTED).await?;
+            return Err(invalid_data("unknown address type"));
+        }
+    };
+    let port = socket.read_u16().await?;
+    Ok(format!("{host}:{port}"))
+}
+
+/// Sends a reply to the request of the client. We do not report the bound address.
+async fn reply(socket: &mut TcpStream, code: u8) -> io::Result<()> {
+    socket
+        .write_all(&[SOCKS_VERSION, code, 0, ADDRESS_IPV4, 0, 0, 0, 0, 0, 0])
+        .await
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn timed_out(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, message)
+}
@@ -0,0 +1,117 @@
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use axum::Router;
+use axum_server::tls_rustls::RustlsConfig;
+use log::{info, warn};
+use rustls::crypto::ring;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{RootCertStore, ServerConfig};
+use tokio::net::TcpListener;
+
+/// How often we check if the certificate files have changed
+const CERTIFICATE_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// The files of the certificate which the local server presents
+#[derive(Debug, Clone)]
+pub struct TlsFiles {
+    /// PEM file of the certificate chain
+    pub certificate: PathBuf,
+    /// PEM file of the private key
+    pub key: PathBuf,
+    /// If set, the remote server must present a client certificate which is signed by these CAs
+    pub client_ca: Option<PathBuf>,
+}
+
+/// Loads the certificate, so the errors are reported before anything is served
+pub(crate) fn load(files: &TlsFiles) -> Result<RustlsConfig, String> {
+    let config = server_config(files).map_err(|err| format!("Cannot load the TLS certificate: {err}"))?;
+    Ok(RustlsConfig::from_config(Arc::new(config)))
+}
+
+/// Serves the websockets over TLS. The certificate is reloaded whenever its files change on disk,
+/// so renewing it does not drop the established websockets.
+pub(crate) async fn serve(listener: TcpListener, app: Router, config: RustlsConfig, files: TlsFiles) {
+    tokio::spawn(watch_certificate(config.clone(), files));
+    let listener = listener.into_std().expect("cannot convert the Axum socket");
+    axum_server::from_tcp_rustls(listener, config)
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+        .await
+        .unwrap()
+}
+
+/// Reloads the certificate when the modification time of its files changes
+async fn watch_certificate(config: RustlsConfig, files: TlsFiles) {
+    let mut last_modified = modified_times(&files);
+    let mut interval = tokio::time::interval(CERTIFICATE_CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        let modified = modified_times(&files);
+        if modified == last_modified {
+            continue;
+        }
+        // If the files are half written, we simply try again on the next tick
+        match server_config(&files) {
+            Ok(server_config) => {
+                config.reload_from_config(Arc::new(server_config));
+                info!("Reloaded the TLS certificate");
+                last_modified = modified;
+            }
+            Err(err) => warn!("Cannot reload the TLS certificate: {err}"),
+        }
+    }
+}
+
+/// Loads the files and builds the TLS config of the server
+fn server_config(files: &TlsFiles) -> Result<ServerConfig, String> {
+    let provider = Arc::new(ring::default_provider());
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(|err| err.to_string())?;
+    let builder = match &files.client_ca {
+        Some(client_ca) => {
+            let mut roots = RootCertStore::empty();
+            for certificate in load_certificates(client_ca)? {
+                roots
+                    .add(certificate)
+                    .map_err(|err| format!("invalid CA in {}: {err}", client_ca.display()))?;
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                .build()
+                .map_err(|err| err.to_string())?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let key = PrivateKeyDer::from_pem_file(&files.key).map_err(|err| {
+        format!(
+            "cannot read private key from {}: {err}",
+            files.key.display()
+        )
+    })?;
+    let mut config = builder
+        .with_single_cert(load_certificates(&files.certificate)?, key)
+        .map_err(|err| format!("invalid certificate: {err}"))?;
+    // Websockets are HTTP/1.1 only
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(config)
+}
+
+fn load_certificates(path: &Path) -> Result<Vec<CertificateDer<'static>>, String> {
+    CertificateDer::pem_file_iter(path)
+        .and_then(|certificates| certificates.collect::<Result<Vec<_>, _>>())
+        .map_err(|err| format!("cannot read certificates from {}: {err}", path.display()))
+}
+
+fn modified_times(files: &TlsFiles) -> [Option<SystemTime>; 3] {
+    let modified = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    [
+        modified(&files.certificate),
+        modified(&files.key),
+        files.client_ca.as_deref().and_then(modified),
+    ]
+}
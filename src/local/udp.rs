@@ -0,0 +1,189 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::{debug, info, trace, warn};
+use parking_lot::Mutex;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::time::Instant;
+use uuid::Uuid;
+
+use crate::admin::{self, Registration};
+use crate::arguments::Mapping;
+use crate::config::tuning;
+use crate::request::{ConnectionRequest, Protocol};
+use crate::shutdown;
+
+use super::socket;
+use super::LocalState;
+
+/// The biggest datagram which we can receive
+const MAX_DATAGRAM_SIZE: usize = 64 * 1024;
+
+/// The sessions of a UDP listener. Each client address has its own connection.
+type UdpSessions = Mutex<HashMap<SocketAddr, Sender<Vec<u8>>>>;
+
+/// Binds the socket of a UDP listener
+pub(crate) async fn bind(mapping: &Mapping) -> Result<UdpSocket, String> {
+    UdpSocket::bind(&mapping.address)
+        .await
+        .map_err(|err| format!("Cannot bind the UDP socket {}: {err}", mapping.address))
+}
+
+/// Receives the datagrams of a UDP listener and forwards each client in its own connection
+pub(crate) async fn handle_udp_socket(
+    udp_socket: UdpSocket,
+    mapping: Mapping,
+    state: &'static LocalState,
+    idle_timeout: Duration,
+) {
+    let udp_socket = Arc::new(udp_socket);
+    info!(
+        "Listening on UDP {} for service {}",
+        mapping.address, mapping.name
+    );
+    let sessions: Arc<UdpSessions> = Arc::default();
+    let mut buffer = vec![0u8; MAX_DATAGRAM_SIZE];
+    loop {
+        // The open sessions are not waited for when shutting down, they are closed with the websockets
+        let received = tokio::select! {
+            received = udp_socket.recv_from(&mut buffer) => received,
+            _ = shutdown::draining() => {
+                info!("Stopped listening on UDP {}", mapping.address);
+                return;
+            }
+        };
+        let (n, client_address) = match received {
+            Ok(result) => result,
+            Err(err) => {
+                warn!("Cannot receive from UDP socket {}: {err}", mapping.address);
+                continue;
+            }
+        };
+        if !state.client_access.allows(client_address.ip()) {
+            debug!("Dropped a datagram of {client_address}: not allowed");
+            continue;
+        }
+        let datagram = buffer[..n].to_owned();
+        // If the client already has a session, simply queue the datagram in it
+        let session = sessions.lock().get(&client_address).cloned();
+        if let Some(session) = session {
+            // UDP is lossy anyway, so drop the datagram if the session is congested
+            if session.try_send(datagram).is_err() {
+                trace!("Dropped a datagram of {client_address}");
+            }
+            continue;
+        }
+        // Otherwise, open a new connection for this client if there is room for it
+        let permit = match state.connection_limits.try_acquire(client_address.ip()) {
+            Ok(permit) => permit,
+            Err(reason) => {
+                debug!("Dropped a datagram of {client_address}: {reason}");
+                continue;
+            }
+        };
+        let session_id = Uuid::new_v4();
+        debug!(
+            "New UDP session {client_address} of {} associated with {session_id}",
+            mapping.name
+        );
+        let request = ConnectionRequest {
+            id: session_id,
+            service: mapping.name.clone(),
+            protocol: Protocol::Udp,
+            destination: None,
+            client_address: Some(client_address),
+            listener_address: udp_socket.local_addr().ok(),
+        };
+        let connection = admin::register(
+            session_id,
+            mapping.name.clone(),
+            Protocol::Udp,
+            Some(client_address),
+        );
+        // Queue the datagrams of the client while the connection is being opened
+        let (datagram_sender, datagram_receiver) = mpsc::channel(tuning().socket_queue_length);
+        datagram_sender.try_send(datagram).unwrap();
+        sessions.lock().insert(client_address, datagram_sender);
+        // Opening the connection might wait for the controller, so do not block the other clients
+        let udp_socket = udp_socket.clone();
+        let sessions = sessions.clone();
+        tokio::task::spawn(async move {
+            let pipe = match socket::open_connection(request, state).await {
+                Some(pipe) => pipe,
+                None => {
+                    sessions.lock().remove(&client_address);
+                    return;
+                }
+            };
+            handle_udp_session(
+                udp_socket,
+                client_address,
+                connection,
+                sessions,
+                idle_timeout,
+                datagram_receiver,
+                pipe.socket_sender,
+                pipe.websocket_receiver,
+            )
+            .await;
+            drop(permit);
+        });
+    }
+}
+
+/// Proxies the datagrams of a single client until it's idle for too long
+#[allow(clippy::too_many_arguments)]
+async fn handle_udp_session(
+    udp_socket: Arc<UdpSocket>,
+    client_address: SocketAddr,
+    connection: Registration,
+    sessions: Arc<UdpSessions>,
+    idle_timeout: Duration,
+    mut datagram_receiver: Receiver<Vec<u8>>,
+    socket_sender: Sender<Vec<u8>>,
+    mut websocket_receiver: Receiver<Vec<u8>>,
+) {
+    let session_id = connection.id;
+    let idle = tokio::time::sleep(idle_timeout);
+    tokio::pin!(idle);
+    let proxy = async {
+        loop {
+            tokio::select! {
+                // Datagrams from the client go into the websocket
+                datagram = datagram_receiver.recv() => match datagram {
+                    Some(datagram) => {
+                        connection.add_bytes_in(datagram.len());
+                        connection.limiter.read(datagram.len()).await;
+                        if socket_sender.send(datagram).await.is_err() {
+                            break; // websocket closed
+                        }
+                    }
+                    None => break,
+                },
+                // And datagrams from the websocket go to the client
+                datagram = websocket_receiver.recv() => match datagram {
+                    Some(datagram) => {
+                        connection.limiter.write(datagram.len()).await;
+                        match udp_socket.send_to(&datagram, client_address).await {
+                            Ok(n) => connection.add_bytes_out(n),
+                            Err(err) => debug!("Cannot send datagram to {client_address}: {err}"),
+                        }
+                    }
+                    None => break, // websocket closed
+                },
+                _ = &mut idle => {
+                    debug!("UDP session {session_id} expired");
+                    break;
+                }
+            }
+            // There was some activity, so extend the session
+            idle.as_mut().reset(Instant::now() + idle_timeout);
+        }
+    };
+    connection.unless_killed(proxy).await;
+    sessions.lock().remove(&client_address);
+    debug!("UDP session {session_id} closed");
+}
@@ -1,26 +1,63 @@
+use std::process::exit;
+
 use clap::Parser;
+use log::error;
 
+mod admin;
 mod arguments;
+mod compression;
+mod config;
 mod local;
+mod metrics;
+mod mux;
+mod proxy_protocol;
+mod ratelimit;
 mod remote;
+mod request;
+mod shutdown;
+mod tcp;
 
 #[tokio::main]
 async fn main() {
     // Initialize tracing and log
     tracing_subscriber::fmt::init();
 
-    // Parse command line arguments
+    // Parse command line arguments and the config file
     let args = arguments::Args::parse();
+    let mut config_file = or_exit(config::ConfigFile::load(args.config.as_deref()));
 
     // Start the server or client
     match args.command {
-        arguments::Commands::Local {
-            tcp_listen_address,
-            cloudflare_listen_address,
-        } => local::start_local_server(&cloudflare_listen_address, &tcp_listen_address).await,
-        arguments::Commands::Server {
-            cloudflare_server_address,
-            forward_address,
-        } => remote::start_remote_controller(cloudflare_server_address, forward_address).await,
+        arguments::Commands::Local(local_args) => {
+            let (local_config, tuning) = or_exit(config_file.local(local_args));
+            config::set_tuning(tuning);
+            tokio::select! {
+                result = local::start_local_server(local_config) => or_exit(result),
+                _ = shutdown::wait_for_shutdown(config::tuning().drain_timeout) => {},
+            }
+        }
+        arguments::Commands::Server(server_args) => {
+            let (server_config, tuning) = or_exit(config_file.server(server_args));
+            config::set_tuning(tuning);
+            tokio::select! {
+                result = remote::start_remote_controller(server_config) => or_exit(result),
+                _ = shutdown::wait_for_shutdown(config::tuning().drain_timeout) => {},
+            }
+        }
+        arguments::Commands::CheckConfig => {
+            if args.config.is_none() {
+                or_exit(Err("no config file is given".to_owned()))
+            }
+            or_exit(config_file.check());
+            println!("Config is valid");
+        }
     };
 }
+
+/// Bails out on configuration errors and the errors which retrying does not fix
+fn or_exit<T>(result: Result<T, String>) -> T {
+    result.unwrap_or_else(|err| {
+        error!("{err}");
+        exit(1);
+    })
+}
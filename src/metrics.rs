@@ -0,0 +1,242 @@
+//! Prometheus metrics of the tunnel.
+//!
+//! The metrics are plain atomics which are rendered in the text exposition format.
+//! They are served on /metrics of a separate address, so they are never exposed through Cloudflare.
+
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use axum::http::header::CONTENT_TYPE;
+use axum::{routing::get, Router};
+use log::info;
+use tokio::net::TcpListener;
+
+use crate::request::DialStatus;
+use crate::shutdown;
+
+/// Upper bounds of the buckets of the connect latency in seconds
+const LATENCY_BUCKETS: [f64; 10] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+
+/// A value which only goes up
+pub(crate) struct Counter(AtomicU64);
+
+impl Counter {
+    const fn new() -> Counter {
+        Counter(AtomicU64::new(0))
+    }
+
+    pub fn add(&self, value: u64) {
+        self.0.fetch_add(value, Ordering::Relaxed);
+    }
+
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Counts the observed durations in the latency buckets
+pub(crate) struct Histogram {
+    /// Each bucket counts the durations which are less than or equal to its bound
+    buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    const fn new() -> Histogram {
+        Histogram {
+            buckets: [const { AtomicU64::new(0) }; LATENCY_BUCKETS.len()],
+            count: AtomicU64::new(0),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        for (bound, bucket) in LATENCY_BUCKETS.iter().zip(&self.buckets) {
+            if seconds <= *bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+}
+
+/// Bytes which are read from the proxied sockets
+pub(crate) static BYTES_IN: Counter = Counter::new();
+/// Bytes which are written in the proxied sockets
+pub(crate) static BYTES_OUT: Counter = Counter::new();
+/// How long it takes to connect to the target of a connection.
+/// On the local server it's measured through the tunnel and on the remote server it's only the dial.
+pub(crate) static CONNECT_DURATION: Histogram = Histogram::new();
+/// How many times the control websocket was established
+pub(crate) static CONTROLLER_CONNECTIONS: Counter = Counter::new();
+/// Connections which the remote server did not open a websocket for in time. Only on the local server.
+pub(crate) static JOIN_TIMEOUTS: Counter = Counter::new();
+/// Clients which were rejected because of the connection caps. Only on the local server.
+pub(crate) static REJECTED_CONNECTIONS: Counter = Counter::new();
+/// Bytes of the data messages before and after they are compressed
+pub(crate) static COMPRESSION_INPUT: Counter = Counter::new();
+pub(crate) static COMPRESSION_OUTPUT: Counter = Counter::new();
+static DIALS_BUSY: Counter = Counter::new();
+static DIALS_DENIED: Counter = Counter::new();
+static DIALS_FAILED: Counter = Counter::new();
+
+/// Counts a target which was not dialed because too many dials are in progress
+pub(crate) fn dial_rejected() {
+    DIALS_BUSY.inc();
+}
+
+/// Counts a target which could not be dialed
+pub(crate) fn dial_failed(status: DialStatus) {
+    match status {
+        DialStatus::Connected => {}
+        DialStatus::Denied => DIALS_DENIED.inc(),
+        DialStatus::Failed => DIALS_FAILED.inc(),
+    }
+}
+
+/// Writes the metrics which both sides have
+fn render(out: &mut String) {
+    write_gauge(
+        out,
+        "rwp_active_connections",
+        "TCP connections which are being proxied right now",
+        shutdown::CONNECTIONS.count(),
+    );
+    write_counter(
+        out,
+        "rwp_bytes_in_total",
+        "Bytes read from the proxied sockets",
+        &BYTES_IN,
+    );
+    write_counter(
+        out,
+        "rwp_bytes_out_total",
+        "Bytes written in the proxied sockets",
+        &BYTES_OUT,
+    );
+    write_counter(
+        out,
+        "rwp_controller_connections_total",
+        "How many times the control websocket was established. More than one means it reconnected",
+        &CONTROLLER_CONNECTIONS,
+    );
+    write_counter(
+        out,
+        "rwp_compression_input_bytes_total",
+        "Bytes of the websocket data messages before compression",
+        &COMPRESSION_INPUT,
+    );
+    write_counter(
+        out,
+        "rwp_compression_output_bytes_total",
+        "Bytes of the websocket data messages after compression",
+        &COMPRESSION_OUTPUT,
+    );
+    // Less than one means that the compression saves bandwidth
+    let input = COMPRESSION_INPUT.get();
+    let ratio = if input == 0 {
+        1.0
+    } else {
+        COMPRESSION_OUTPUT.get() as f64 / input as f64
+    };
+    let _ = writeln!(
+        out,
+        "# HELP rwp_compression_ratio Compressed size of the websocket data messages divided by their original size"
+    );
+    let _ = writeln!(out, "# TYPE rwp_compression_ratio gauge");
+    let _ = writeln!(out, "rwp_compression_ratio {ratio}");
+    // The dial failures share a name and are told apart by their reason
+    let _ = writeln!(
+        out,
+        "# HELP rwp_dial_failures_total Targets which could not be dialed"
+    );
+    let _ = writeln!(out, "# TYPE rwp_dial_failures_total counter");
+    let _ = writeln!(
+        out,
+        "rwp_dial_failures_total{{reason=\"busy\"}} {}",
+        DIALS_BUSY.get()
+    );
+    let _ = writeln!(
+        out,
+        "rwp_dial_failures_total{{reason=\"denied\"}} {}",
+        DIALS_DENIED.get()
+    );
+    let _ = writeln!(
+        out,
+        "rwp_dial_failures_total{{reason=\"failed\"}} {}",
+        DIALS_FAILED.get()
+    );
+    // And at last the latency
+    let histogram = &CONNECT_DURATION;
+    let name = "rwp_connect_duration_seconds";
+    let _ = writeln!(
+        out,
+        "# HELP {name} How long it took to connect to the target of a connection"
+    );
+    let _ = writeln!(out, "# TYPE {name} histogram");
+    for (bound, bucket) in LATENCY_BUCKETS.iter().zip(&histogram.buckets) {
+        let _ = writeln!(
+            out,
+            "{name}_bucket{{le=\"{bound}\"}} {}",
+            bucket.load(Ordering::Relaxed)
+        );
+    }
+    let count = histogram.count.load(Ordering::Relaxed);
+    let sum = histogram.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+    let _ = writeln!(out, "{name}_bucket{{le=\"+Inf\"}} {count}");
+    let _ = writeln!(out, "{name}_sum {sum}");
+    let _ = writeln!(out, "{name}_count {count}");
+}
+
+pub(crate) fn write_counter(out: &mut String, name: &str, help: &str, counter: &Counter) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} counter");
+    let _ = writeln!(out, "{name} {}", counter.get());
+}
+
+/// Writes a gauge. Used for the values which are read from the state of each side.
+pub(crate) fn write_gauge(out: &mut String, name: &str, help: &str, value: usize) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} gauge");
+    let _ = writeln!(out, "{name} {value}");
+}
+
+/// Binds the socket of the metrics, so a bad address stops the program before anything else
+pub(crate) async fn bind(address: &str) -> Result<TcpListener, String> {
+    TcpListener::bind(address)
+        .await
+        .map_err(|err| format!("Cannot bind the metrics socket {address}: {err}"))
+}
+
+/// Serves the metrics on /metrics of the given socket.
+/// The metrics which only one side has are written by extra.
+pub(crate) async fn serve<F>(listener: TcpListener, extra: F)
+where
+    F: Fn(&mut String) + Clone + Send + Sync + 'static,
+{
+    if let Ok(address) = listener.local_addr() {
+        info!("Serving metrics on {address}");
+    }
+    let app = Router::new().route(
+        "/metrics",
+        get(move || {
+            let extra = extra.clone();
+            async move {
+                let mut out = String::new();
+                render(&mut out);
+                extra(&mut out);
+                ([(CONTENT_TYPE, "text/plain; version=0.0.4")], out)
+            }
+        }),
+    );
+    axum::serve(listener, app).await.unwrap()
+}
@@ -0,0 +1,409 @@
+//! Multiplexing of several streams over a single websocket.
+//!
+//! Each websocket binary message is a frame which looks like this:
+//! ```text
+//! +------+-----------------+---------+
+//! | type | stream id (u32) | payload |
+//! +------+-----------------+---------+
+//! ```
+//! The local server opens the streams and the remote server accepts them.
+//! Each side can only send as many bytes in a stream as the other side has granted
+//! with window frames. So, a slow stream cannot block the whole websocket.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+
+use log::{debug, warn};
+use parking_lot::Mutex;
+use tokio::sync::{mpsc, oneshot, Semaphore};
+
+use crate::request::{ConnectionRequest, DialStatus};
+
+/// How many bytes each side can send in a stream before getting a window update
+pub(crate) const STREAM_WINDOW_SIZE: u32 = 256 * 1024;
+/// How many frames can be queued to be written in the websocket
+pub(crate) const FRAME_QUEUE_LENGTH: usize = 128;
+
+const FRAME_OPEN: u8 = 0;
+const FRAME_DATA: u8 = 1;
+const FRAME_CLOSE: u8 = 2;
+const FRAME_WINDOW: u8 = 3;
+const FRAME_DIALED: u8 = 4;
+
+/// Size of type and stream id
+const FRAME_HEADER_SIZE: usize = 5;
+
+/// A single frame which is sent over the websocket
+#[derive(Debug)]
+pub(crate) enum Frame {
+    /// Open a new stream for the requested connection
+    Open {
+        stream_id: u32,
+        request: ConnectionRequest,
+    },
+    /// Some data in a stream
+    Data { stream_id: u32, payload: Vec<u8> },
+    /// The stream is closed and no more data is going to be sent or accepted
+    Close { stream_id: u32 },
+    /// The receiver consumed this many bytes and the sender can send more
+    Window { stream_id: u32, increment: u32 },
+    /// The remote server reports the result of dialing the target of a stream
+    Dialed { stream_id: u32, status: DialStatus },
+}
+
+impl Frame {
+    /// Converts the frame to the bytes which should be sent in the websocket
+    pub fn encode(&self) -> Vec<u8> {
+        let encoded_request;
+        let (frame_type, stream_id, payload): (u8, u32, &[u8]) = match self {
+            Frame::Open { stream_id, request } => {
+                encoded_request = request.encode();
+                (FRAME_OPEN, *stream_id, encoded_request.as_bytes())
+            }
+            Frame::Data { stream_id, payload } => (FRAME_DATA, *stream_id, payload),
+            Frame::Close { stream_id } => (FRAME_CLOSE, *stream_id, &[]),
+            Frame::Window {
+                stream_id,
+                increment,
+            } => (FRAME_WINDOW, *stream_id, &increment.to_be_bytes()),
+            Frame::Dialed { stream_id, status } => (FRAME_DIALED, *stream_id, &[status.to_code()]),
+        };
+        let mut result = Vec::with_capacity(FRAME_HEADER_SIZE + payload.len());
+        result.push(frame_type);
+        result.extend_from_slice(&stream_id.to_be_bytes());
+        result.extend_from_slice(payload);
+        result
+    }
+
+    /// Parses a frame which is received from the websocket
+    pub fn decode(mut data: Vec<u8>) -> Option<Frame> {
+        if data.len() < FRAME_HEADER_SIZE {
+            return None;
+        }
+        let frame_type = data[0];
+        let stream_id = u32::from_be_bytes(data[1..FRAME_HEADER_SIZE].try_into().unwrap());
+        let payload = &data[FRAME_HEADER_SIZE..];
+        match frame_type {
+            FRAME_OPEN => Some(Frame::Open {
+                stream_id,
+                request: ConnectionRequest::decode(payload).ok()?,
+            }),
+            FRAME_DATA => {
+                data.drain(..FRAME_HEADER_SIZE);
+                Some(Frame::Data {
+                    stream_id,
+                    payload: data,
+                })
+            }
+            FRAME_CLOSE => Some(Frame::Close { stream_id }),
+            FRAME_WINDOW => Some(Frame::Window {
+                stream_id,
+                increment: u32::from_be_bytes(payload.try_into().ok()?),
+            }),
+            FRAME_DIALED => Some(Frame::Dialed {
+                stream_id,
+                status: DialStatus::from_code(*payload.first()?)?,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// The state of a stream in a session
+struct MuxStream {
+    /// Data which is received from the websocket is sent here
+    incoming: mpsc::UnboundedSender<Vec<u8>>,
+    /// How many bytes we can send to the other side
+    credits: Arc<Semaphore>,
+    /// The dial result of the streams which we have opened is reported here
+    dialed: Option<oneshot::Sender<DialStatus>>,
+}
+
+/// A multiplexed websocket which carries several streams
+pub(crate) struct MuxSession {
+    /// Frames sent here are written to the websocket
+    frames: mpsc::Sender<Frame>,
+    /// Open streams of this session
+    streams: Mutex<HashMap<u32, MuxStream>>,
+    /// The id of the next stream which we open
+    next_stream_id: AtomicU32,
+}
+
+impl MuxSession {
+    /// Creates a new session. The returned receiver must be drained into the websocket.
+    pub fn new() -> (Arc<MuxSession>, mpsc::Receiver<Frame>) {
+        let (frames, frames_receiver) = mpsc::channel(FRAME_QUEUE_LENGTH);
+        let session = Arc::new(MuxSession {
+            frames,
+            streams: Mutex::new(HashMap::new()),
+            next_stream_id: AtomicU32::new(0),
+        });
+        (session, frames_receiver)
+    }
+
+    /// How many streams are open right now
+    pub fn stream_count(&self) -> usize {
+        self.streams.lock().len()
+    }
+
+    /// Opens a new stream and notifies the other side.
+    /// The streams data is read from to_peer and written to from_peer.
+    pub async fn open_stream(
+        self: &Arc<Self>,
+        request: ConnectionRequest,
+        to_peer: mpsc::Receiver<Vec<u8>>,
+        from_peer: mpsc::Sender<Vec<u8>>,
+        dialed: oneshot::Sender<DialStatus>,
+    ) {
+        let stream_id = self.next_stream_id.fetch_add(1, Ordering::Relaxed);
+        self.attach_stream(stream_id, to_peer, from_peer, Some(dialed));
+        let _ = self.frames.send(Frame::Open { stream_id, request }).await;
+    }
+
+    /// Starts pumping the data of a stream between the channels and the websocket
+    pub fn attach_stream(
+        self: &Arc<Self>,
+        stream_id: u32,
+        mut to_peer: mpsc::Receiver<Vec<u8>>,
+        from_peer: mpsc::Sender<Vec<u8>>,
+        dialed: Option<oneshot::Sender<DialStatus>>,
+    ) {
+        let (incoming, mut incoming_receiver) = mpsc::unbounded_channel();
+        let credits = Arc::new(Semaphore::new(STREAM_WINDOW_SIZE as usize));
+        self.streams.lock().insert(
+            stream_id,
+            MuxStream {
+                incoming,
+                credits: credits.clone(),
+                dialed,
+            },
+        );
+        // Send the data to the other side as long as we have credit
+        let session = self.clone();
+        tokio::spawn(async move {
+            while let Some(payload) = to_peer.recv().await {
+                // An empty payload means EOF and it does not need any credit
+                if payload.is_empty() {
+                    let frame = Frame::Data {
+                        stream_id,
+                        payload,
+                    };
+                    if session.frames.send(frame).await.is_err() {
+                        return; // session closed
+                    }
+                    continue;
+                }
+                // Each chunk is at most as big as the window
+                for chunk in payload.chunks(STREAM_WINDOW_SIZE as usize) {
+                    match credits.acquire_many(chunk.len() as u32).await {
+                        Ok(permit) => permit.forget(),
+                        Err(_) => return, // stream closed
+                    }
+                    let frame = Frame::Data {
+                        stream_id,
+                        payload: chunk.to_owned(),
+                    };
+                    if session.frames.send(frame).await.is_err() {
+                        return; // session closed
+                    }
+                }
+            }
+            session.close_stream(stream_id, true).await;
+        });
+        // Receive the data from the other side and grant more credit once it's consumed
+        let session = self.clone();
+        tokio::spawn(async move {
+            while let Some(payload) = incoming_receiver.recv().await {
+                let increment = payload.len() as u32;
+                if from_peer.send(payload).await.is_err() {
+                    session.close_stream(stream_id, true).await;
+                    return;
+                }
+                if increment == 0 {
+                    continue; // EOF took no credit
+                }
+                let frame = Frame::Window {
+                    stream_id,
+                    increment,
+                };
+                if session.frames.send(frame).await.is_err() {
+                    return; // session closed
+                }
+            }
+        });
+    }
+
+    /// Tells the other side the result of dialing the target of a stream
+    pub async fn send_dialed(&self, stream_id: u32, status: DialStatus) {
+        let _ = self.frames.send(Frame::Dialed { stream_id, status }).await;
+    }
+
+    /// Removes a stream from the session. If notify_peer is set, the other side is notified too.
+    pub async fn close_stream(&self, stream_id: u32, notify_peer: bool) {
+        let stream = self.streams.lock().remove(&stream_id);
+        if let Some(stream) = stream {
+            debug!("Closing mux stream {stream_id}");
+            stream.credits.close();
+            if notify_peer {
+                let _ = self.frames.send(Frame::Close { stream_id }).await;
+            }
+        }
+    }
+
+    /// Closes all streams of the session. Should be called when the websocket is closed.
+    pub fn close(&self) {
+        for (_, stream) in self.streams.lock().drain() {
+            stream.credits.close();
+        }
+    }
+
+    /// Handles a frame which is received from the websocket.
+    /// Open frames must be handled by the caller so they are returned.
+    pub async fn dispatch(&self, frame: Frame) -> Option<Frame> {
+        match frame {
+            Frame::Data { stream_id, payload } => {
+                // The stream might be closed by us, so ignore the missing ones
+                if let Some(stream) = self.streams.lock().get(&stream_id) {
+                    let _ = stream.incoming.send(payload);
+                }
+            }
+            Frame::Window {
+                stream_id,
+                increment,
+            } => {
+                if let Some(stream) = self.streams.lock().get(&stream_id) {
+                    stream.credits.add_permits(increment as usize);
+                }
+            }
+            Frame::Dialed { stream_id, status } => {
+                let dialed = self
+                    .streams
+                    .lock()
+                    .get_mut(&stream_id)
+                    .and_then(|stream| stream.dialed.take());
+                if let Some(dialed) = dialed {
+                    let _ = dialed.send(status);
+                }
+            }
+            Frame::Close { stream_id } => self.close_stream(stream_id, false).await,
+            Frame::Open { .. } => return Some(frame),
+        }
+        None
+    }
+}
+
+/// Decodes a websocket payload and logs the invalid ones
+pub(crate) fn decode_or_warn(data: Vec<u8>) -> Option<Frame> {
+    let frame = Frame::decode(data);
+    if frame.is_none() {
+        warn!("Received an invalid mux frame");
+    }
+    frame
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::request::Protocol;
+
+    fn round_trip(frame: &Frame) -> Frame {
+        Frame::decode(frame.encode()).expect("frame should decode")
+    }
+
+    #[test]
+    fn frames_round_trip() {
+        let request = ConnectionRequest {
+            id: Uuid::new_v4(),
+            service: "ssh".to_string(),
+            protocol: Protocol::Udp,
+            destination: Some("example.com:22".to_string()),
+            client_address: Some("192.0.2.1:4000".parse().unwrap()),
+            listener_address: None,
+        };
+        match round_trip(&Frame::Open {
+            stream_id: 1,
+            request: request.clone(),
+        }) {
+            Frame::Open {
+                stream_id: 1,
+                request: decoded,
+            } => assert_eq!(decoded.encode(), request.encode()),
+            frame => panic!("unexpected frame {frame:?}"),
+        }
+        let payload = vec![1, 2, 3];
+        assert!(matches!(
+            round_trip(&Frame::Data { stream_id: 2, payload: payload.clone() }),
+            Frame::Data { stream_id: 2, payload: decoded } if decoded == payload
+        ));
+        // The empty data frames are EOFs and must survive too
+        assert!(matches!(
+            round_trip(&Frame::Data { stream_id: 2, payload: Vec::new() }),
+            Frame::Data { stream_id: 2, payload } if payload.is_empty()
+        ));
+        assert!(matches!(
+            round_trip(&Frame::Close {
+                stream_id: u32::MAX
+            }),
+            Frame::Close {
+                stream_id: u32::MAX
+            }
+        ));
+        assert!(matches!(
+            round_trip(&Frame::Window {
+                stream_id: 3,
+                increment: STREAM_WINDOW_SIZE
+            }),
+            Frame::Window {
+                stream_id: 3,
+                increment: STREAM_WINDOW_SIZE
+            }
+        ));
+        for status in [
+            DialStatus::Connected,
+            DialStatus::Denied,
+            DialStatus::Failed,
+        ] {
+            assert!(matches!(
+                round_trip(&Frame::Dialed { stream_id: 4, status }),
+                Frame::Dialed { stream_id: 4, status: decoded } if decoded == status
+            ));
+        }
+    }
+
+    #[test]
+    fn truncated_frames() {
+        let request = ConnectionRequest::decode(
+            br#"{"id":"00000000-0000-0000-0000-000000000000","service":"ssh"}"#,
+        );
+        let frames = [
+            Frame::Open {
+                stream_id: 1,
+                request: request.unwrap(),
+            },
+            Frame::Close { stream_id: 1 },
+            Frame::Window {
+                stream_id: 1,
+                increment: 10,
+            },
+            Frame::Dialed {
+                stream_id: 1,
+                status: DialStatus::Connected,
+            },
+        ];
+        for frame in frames {
+            let encoded = frame.encode();
+            for length in 0..encoded.len() {
+                let decoded = Frame::decode(encoded[..length].to_vec());
+                assert!(
+                    decoded.is_none(),
+                    "{decoded:?} from {length} bytes of {frame:?}"
+                );
+            }
+        }
+        assert!(Frame::decode(vec![FRAME_DIALED, 0, 0, 0, 1, 9]).is_none());
+        assert!(Frame::decode(vec![9, 0, 0, 0, 1]).is_none());
+    }
+}
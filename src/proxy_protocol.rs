@@ -0,0 +1,328 @@
+//! The PROXY protocol which tells a TCP server the address of the original client.
+//!
+//! The remote server can send the header to the forward targets, and the local server can read it
+//! from the load balancer in front of its TCP listeners.
+//! See https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+
+use serde::Deserialize;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// The first bytes of a version 2 header
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+/// The longest version 1 header including its CRLF
+const V1_MAX_LENGTH: usize = 107;
+
+const V2_COMMAND_LOCAL: u8 = 0x20;
+const V2_COMMAND_PROXY: u8 = 0x21;
+const V2_FAMILY_UNSPEC: u8 = 0x00;
+const V2_FAMILY_TCP4: u8 = 0x11;
+const V2_FAMILY_TCP6: u8 = 0x21;
+
+/// Which version of the header is sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum Version {
+    /// The human readable header
+    V1,
+    /// The binary header
+    V2,
+}
+
+impl FromStr for Version {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "v1" | "1" => Ok(Version::V1),
+            "v2" | "2" => Ok(Version::V2),
+            _ => Err(format!(
+                "unknown PROXY protocol version {value}, use v1 or v2"
+            )),
+        }
+    }
+}
+
+impl TryFrom<String> for Version {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+/// Makes the header of a connection from source to destination.
+/// If any of the addresses is unknown, the header tells the server to use the real address of the socket.
+pub(crate) fn encode(
+    version: Version,
+    source: Option<SocketAddr>,
+    destination: Option<SocketAddr>,
+) -> Vec<u8> {
+    let addresses = match (source, destination) {
+        (Some(source), Some(destination)) => Some(same_family(source, destination)),
+        _ => None,
+    };
+    match version {
+        Version::V1 => match addresses {
+            Some((source, destination)) => format!(
+                "PROXY {} {} {} {} {}\r\n",
+                if source.is_ipv4() { "TCP4" } else { "TCP6" },
+                source.ip(),
+                destination.ip(),
+                source.port(),
+                destination.port()
+            )
+            .into_bytes(),
+            None => b"PROXY UNKNOWN\r\n".to_vec(),
+        },
+        Version::V2 => {
+            let mut header = V2_SIGNATURE.to_vec();
+            let body = match addresses {
+                Some((source, destination)) => {
+                    header.push(V2_COMMAND_PROXY);
+                    let mut body = Vec::with_capacity(36);
+                    match (source.ip(), destination.ip()) {
+                        (IpAddr::V4(source_ip), IpAddr::V4(destination_ip)) => {
+                            header.push(V2_FAMILY_TCP4);
+                            body.extend_from_slice(&source_ip.octets());
+                            body.extend_from_slice(&destination_ip.octets());
+                        }
+                        (source_ip, destination_ip) => {
+                            header.push(V2_FAMILY_TCP6);
+                            body.extend_from_slice(&to_ipv6(source_ip).octets());
+                            body.extend_from_slice(&to_ipv6(destination_ip).octets());
+                        }
+                    }
+                    body.extend_from_slice(&source.port().to_be_bytes());
+                    body.extend_from_slice(&destination.port().to_be_bytes());
+                    body
+                }
+                None => {
+                    header.push(V2_COMMAND_LOCAL);
+                    header.push(V2_FAMILY_UNSPEC);
+                    Vec::new()
+                }
+            };
+            header.extend_from_slice(&(body.len() as u16).to_be_bytes());
+            header.extend_from_slice(&body);
+            header
+        }
+    }
+}
+
+/// Both addresses of a header must have the same family, so IPv4 is mapped to IPv6 if needed
+fn same_family(source: SocketAddr, destination: SocketAddr) -> (SocketAddr, SocketAddr) {
+    if source.is_ipv4() == destination.is_ipv4() {
+        return (source, destination);
+    }
+    let map =
+        |address: SocketAddr| SocketAddr::new(IpAddr::V6(to_ipv6(address.ip())), address.port());
+    (map(source), map(destination))
+}
+
+fn to_ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(v4) => v4.to_ipv6_mapped(),
+        IpAddr::V6(v6) => v6,
+    }
+}
+
+/// The source and destination addresses of a header
+pub(crate) type Addresses = (SocketAddr, SocketAddr);
+
+/// Reads the header of either version from the socket.
+/// Returns the source and destination addresses, or None if the proxy did not tell them, like in health checks.
+/// Nothing after the header is read.
+pub(crate) async fn read_header<R: AsyncRead + Unpin>(
+    socket: &mut R,
+) -> io::Result<Option<Addresses>> {
+    let mut signature = [0u8; 12];
+    socket.read_exact(&mut signature[..6]).await?;
+    if &signature[..6] == b"PROXY " {
+        return read_v1(socket).await;
+    }
+    socket.read_exact(&mut signature[6..]).await?;
+    if signature == V2_SIGNATURE {
+        return read_v2(socket).await;
+    }
+    Err(invalid_header("no PROXY protocol header"))
+}
+
+/// Reads the rest of a version 1 header after "PROXY "
+async fn read_v1<R: AsyncRead + Unpin>(socket: &mut R) -> io::Result<Option<Addresses>> {
+    // Read byte by byte, so we do not consume the data of the client
+    let mut line = Vec::new();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LENGTH - 6 {
+            return Err(invalid_header("PROXY header is too long"));
+        }
+        line.push(socket.read_u8().await?);
+    }
+    let line = std::str::from_utf8(&line[..line.len() - 2])
+        .map_err(|_| invalid_header("PROXY header is not ASCII"))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields.as_slice() {
+        ["UNKNOWN", ..] => Ok(None),
+        ["TCP4" | "TCP6", source_ip, destination_ip, source_port, destination_port] => {
+            let source = parse_v1_address(source_ip, source_port)?;
+            let destination = parse_v1_address(destination_ip, destination_port)?;
+            Ok(Some((source, destination)))
+        }
+        _ => Err(invalid_header("invalid PROXY header")),
+    }
+}
+
+fn parse_v1_address(ip: &str, port: &str) -> io::Result<SocketAddr> {
+    let ip: IpAddr = ip
+        .parse()
+        .map_err(|_| invalid_header("invalid address in PROXY header"))?;
+    let port: u16 = port
+        .parse()
+        .map_err(|_| invalid_header("invalid port in PROXY header"))?;
+    Ok(SocketAddr::new(ip, port))
+}
+
+/// Reads the rest of a version 2 header after its signature
+async fn read_v2<R: AsyncRead + Unpin>(socket: &mut R) -> io::Result<Option<Addresses>> {
+    let mut header = [0u8; 4];
+    socket.read_exact(&mut header).await?;
+    let (command, family) = (header[0], header[1]);
+    let length = u16::from_be_bytes([header[2], header[3]]) as usize;
+    // The body might also have TLVs after the addresses, which we do not care about
+    let mut body = vec![0u8; length];
+    socket.read_exact(&mut body).await?;
+    if command == V2_COMMAND_LOCAL {
+        return Ok(None);
+    }
+    if command != V2_COMMAND_PROXY {
+        return Err(invalid_header("unknown command in PROXY header"));
+    }
+    match family {
+        V2_FAMILY_TCP4 if body.len() >= 12 => {
+            let ip = |at: usize| {
+                IpAddr::V4(Ipv4Addr::new(
+                    body[at],
+                    body[at + 1],
+                    body[at + 2],
+                    body[at + 3],
+                ))
+            };
+            let port = |at: usize| u16::from_be_bytes([body[at], body[at + 1]]);
+            Ok(Some((
+                SocketAddr::new(ip(0), port(8)),
+                SocketAddr::new(ip(4), port(10)),
+            )))
+        }
+        V2_FAMILY_TCP6 if body.len() >= 36 => {
+            let ip = |at: usize| {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(&body[at..at + 16]);
+                IpAddr::V6(Ipv6Addr::from(octets))
+            };
+            let port = |at: usize| u16::from_be_bytes([body[at], body[at + 1]]);
+            Ok(Some((
+                SocketAddr::new(ip(0), port(32)),
+                SocketAddr::new(ip(16), port(34)),
+            )))
+        }
+        // Other families like UDP or unix sockets do not have an address we can use
+        _ => Ok(None),
+    }
+}
+
+fn invalid_header(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(value: &str) -> SocketAddr {
+        value.parse().unwrap()
+    }
+
+    /// Reads a header from the bytes and returns what is left after it
+    async fn read(mut bytes: &[u8]) -> io::Result<(Option<Addresses>, &[u8])> {
+        let addresses = read_header(&mut bytes).await?;
+        Ok((addresses, bytes))
+    }
+
+    #[tokio::test]
+    async fn round_trip() {
+        let cases = [
+            ("192.0.2.1:4000", "198.51.100.2:22"),
+            ("[2001:db8::1]:4000", "[2001:db8::2]:443"),
+        ];
+        for version in [Version::V1, Version::V2] {
+            for (source, destination) in cases {
+                let (source, destination) = (address(source), address(destination));
+                let mut bytes = encode(version, Some(source), Some(destination));
+                bytes.extend_from_slice(b"data");
+                let (addresses, rest) = read(&bytes).await.unwrap();
+                assert_eq!(addresses, Some((source, destination)), "{version:?}");
+                assert_eq!(rest, b"data");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn mixed_families_are_mapped() {
+        let source = address("192.0.2.1:4000");
+        let destination = address("[2001:db8::2]:443");
+        let mapped = address("[::ffff:192.0.2.1]:4000");
+        for version in [Version::V1, Version::V2] {
+            let bytes = encode(version, Some(source), Some(destination));
+            let (addresses, _) = read(&bytes).await.unwrap();
+            assert_eq!(addresses, Some((mapped, destination)), "{version:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn unknown_addresses() {
+        for version in [Version::V1, Version::V2] {
+            let mut bytes = encode(version, None, Some(address("192.0.2.1:22")));
+            bytes.extend_from_slice(b"data");
+            let (addresses, rest) = read(&bytes).await.unwrap();
+            assert_eq!(addresses, None, "{version:?}");
+            assert_eq!(rest, b"data");
+        }
+    }
+
+    #[tokio::test]
+    async fn v1_length_limit() {
+        // The longest valid header is exactly at the limit
+        let longest = format!(
+            "PROXY TCP6 {0} {0} 65535 65535\r\n",
+            "ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff"
+        );
+        assert!(longest.len() <= V1_MAX_LENGTH);
+        assert!(read(longest.as_bytes()).await.unwrap().0.is_some());
+        let padded = format!("PROXY UNKNOWN {}\r\n", "a".repeat(V1_MAX_LENGTH - 16));
+        assert_eq!(padded.len(), V1_MAX_LENGTH);
+        assert_eq!(read(padded.as_bytes()).await.unwrap().0, None);
+        let too_long = format!("PROXY UNKNOWN {}\r\n", "a".repeat(V1_MAX_LENGTH));
+        let error = read(too_long.as_bytes()).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn invalid_headers() {
+        let cases: [&[u8]; 4] = [
+            b"GET / HTTP/1.1\r\n\r\n",
+            b"PROXY TCP4 192.0.2.1 198.51.100.2 4000\r\n",
+            b"PROXY TCP4 192.0.2.1 198.51.100.2 4000 70000\r\n",
+            b"PROXY TCP4 192.0.2.1",
+        ];
+        for bytes in cases {
+            assert!(
+                read(bytes).await.is_err(),
+                "{:?}",
+                String::from_utf8_lossy(bytes)
+            );
+        }
+    }
+}
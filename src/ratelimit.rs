@@ -0,0 +1,265 @@
+//! Token bucket bandwidth limits of the proxied sockets.
+//!
+//! There are three levels of limits: the whole process, each listener (service) and each connection.
+//! A listener can have its own limit, otherwise the limit of the listeners is used.
+//! A socket waits for the tokens of every level, so the strictest one wins.
+//! Upload is the data which goes from the client to the target and download is the other way around.
+//! The buckets read their rate on each use, so the limits can be changed at runtime.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+
+use crate::arguments::RateLimit;
+use crate::config::tuning;
+
+/// The rate of a listener which does not have its own limit
+const UNSET: u64 = u64::MAX;
+
+/// The limits of a level in bytes per second. 0 means unlimited.
+struct Rate {
+    upload: AtomicU64,
+    download: AtomicU64,
+}
+
+impl Rate {
+    const fn new(value: u64) -> Rate {
+        Rate {
+            upload: AtomicU64::new(value),
+            download: AtomicU64::new(value),
+        }
+    }
+
+    fn set(&self, limit: RateLimit) {
+        self.upload.store(limit.upload, Ordering::Relaxed);
+        self.download.store(limit.download, Ordering::Relaxed);
+    }
+
+    fn get(&self) -> RateLimit {
+        RateLimit {
+            upload: self.upload.load(Ordering::Relaxed),
+            download: self.download.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Makes the listener use the limit of the listeners again
+    fn unset(&self) {
+        self.upload.store(UNSET, Ordering::Relaxed);
+        self.download.store(UNSET, Ordering::Relaxed);
+    }
+
+    fn is_set(&self) -> bool {
+        self.upload.load(Ordering::Relaxed) != UNSET
+    }
+}
+
+static GLOBAL_RATE: Rate = Rate::new(0);
+static LISTENER_RATE: Rate = Rate::new(0);
+static CONNECTION_RATE: Rate = Rate::new(0);
+
+static GLOBAL: Buckets = Buckets::new(&GLOBAL_RATE, None);
+/// The rates of each listener by its service name. They are unset unless the listener has its own limit.
+static SERVICE_RATES: OnceLock<Mutex<HashMap<String, &'static Rate>>> = OnceLock::new();
+/// The buckets of each listener by its service name
+static LISTENERS: OnceLock<Mutex<HashMap<String, Arc<Buckets>>>> = OnceLock::new();
+/// On the local server the sockets read the uploads, and on the remote server they read the downloads
+static UPLOAD_IS_READ: AtomicBool = AtomicBool::new(false);
+
+/// Sets the initial limits from the tuning.
+/// The local server should pass true because it reads the uploads from its clients.
+pub(crate) fn init(upload_is_read: bool) {
+    UPLOAD_IS_READ.store(upload_is_read, Ordering::Relaxed);
+    GLOBAL_RATE.set(tuning().global_rate_limit);
+    LISTENER_RATE.set(tuning().listener_rate_limit);
+    CONNECTION_RATE.set(tuning().connection_rate_limit);
+    for (service, limit) in &tuning().service_rate_limits {
+        service_rate(service).set(*limit);
+    }
+}
+
+/// Returns the rate of a listener. It's created on the first use and lives as long as the program.
+fn service_rate(service: &str) -> &'static Rate {
+    SERVICE_RATES
+        .get_or_init(Mutex::default)
+        .lock()
+        .entry(service.to_owned())
+        .or_insert_with(|| Box::leak(Box::new(Rate::new(UNSET))))
+}
+
+/// The limits of every level, as shown and changed in the admin API
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Limits {
+    pub global: Option<RateLimit>,
+    /// The limit of the listeners which do not have their own
+    pub listener: Option<RateLimit>,
+    /// The listeners which have their own limit by their service name.
+    /// When changing the limits, null makes a listener use the limit of the listeners again.
+    #[serde(default)]
+    pub listeners: HashMap<String, Option<RateLimit>>,
+    pub connection: Option<RateLimit>,
+}
+
+/// Returns the limits which are in effect right now
+pub(crate) fn limits() -> Limits {
+    let listeners = SERVICE_RATES
+        .get_or_init(Mutex::default)
+        .lock()
+        .iter()
+        .filter(|(_, rate)| rate.is_set())
+        .map(|(service, rate)| (service.clone(), Some(rate.get())))
+        .collect();
+    Limits {
+        global: Some(GLOBAL_RATE.get()),
+        listener: Some(LISTENER_RATE.get()),
+        listeners,
+        connection: Some(CONNECTION_RATE.get()),
+    }
+}
+
+/// Changes the given limits. The open connections are limited by the new values right away.
+pub(crate) fn set_limits(limits: Limits) {
+    if let Some(limit) = limits.global {
+        GLOBAL_RATE.set(limit);
+    }
+    if let Some(limit) = limits.listener {
+        LISTENER_RATE.set(limit);
+    }
+    for (service, limit) in limits.listeners {
+        match limit {
+            Some(limit) => service_rate(&service).set(limit),
+            None => service_rate(&service).unset(),
+        }
+    }
+    if let Some(limit) = limits.connection {
+        CONNECTION_RATE.set(limit);
+    }
+}
+
+/// A single token bucket which can hold up to one second of its rate
+struct Bucket {
+    rate: &'static AtomicU64,
+    /// Used while the rate is unset
+    fallback: Option<&'static AtomicU64>,
+    /// The tokens which are left and when they were refilled.
+    /// The tokens can go negative, which makes the next users wait for the debt too.
+    state: Mutex<(f64, Option<Instant>)>,
+}
+
+impl Bucket {
+    const fn new(rate: &'static AtomicU64, fallback: Option<&'static AtomicU64>) -> Bucket {
+        Bucket {
+            rate,
+            fallback,
+            state: Mutex::new((0.0, None)),
+        }
+    }
+
+    fn rate(&self) -> u64 {
+        match (self.rate.load(Ordering::Relaxed), self.fallback) {
+            (UNSET, Some(fallback)) => fallback.load(Ordering::Relaxed),
+            (UNSET, None) => 0,
+            (rate, _) => rate,
+        }
+    }
+
+    /// Takes n tokens and waits until they are paid for
+    async fn take(&self, n: usize) {
+        let rate = self.rate() as f64;
+        if rate == 0.0 {
+            return;
+        }
+        let tokens = {
+            let mut state = self.state.lock();
+            let now = Instant::now();
+            let (tokens, refilled) = *state;
+            // A bucket which is never used starts full
+            let tokens = match refilled {
+                Some(refilled) => tokens + now.duration_since(refilled).as_secs_f64() * rate,
+                None => rate,
+            };
+            let tokens = tokens.min(rate) - n as f64;
+            *state = (tokens, Some(now));
+            tokens
+        };
+        if tokens < 0.0 {
+            tokio::time::sleep(Duration::from_secs_f64(-tokens / rate)).await;
+        }
+    }
+}
+
+struct Buckets {
+    upload: Bucket,
+    download: Bucket,
+}
+
+impl Buckets {
+    /// Makes the buckets of a rate. If the rate is unset, the buckets use the fallback rate.
+    const fn new(rate: &'static Rate, fallback: Option<&'static Rate>) -> Buckets {
+        Buckets {
+            upload: Bucket::new(&rate.upload, match fallback {
+                Some(fallback) => Some(&fallback.upload),
+                None => None,
+            }),
+            download: Bucket::new(&rate.download, match fallback {
+                Some(fallback) => Some(&fallback.download),
+                None => None,
+            }),
+        }
+    }
+}
+
+/// The buckets which a single connection goes through
+pub(crate) struct Limiter {
+    listener: Arc<Buckets>,
+    connection: Buckets,
+}
+
+impl Limiter {
+    pub fn new(service: &str) -> Limiter {
+        let listener = LISTENERS
+            .get_or_init(Mutex::default)
+            .lock()
+            .entry(service.to_owned())
+            .or_insert_with(|| Arc::new(Buckets::new(service_rate(service), Some(&LISTENER_RATE))))
+            .clone();
+        Limiter {
+            listener,
+            connection: Buckets::new(&CONNECTION_RATE, None),
+        }
+    }
+
+    /// Waits before the bytes which are read from the socket are sent to the other side
+    pub async fn read(&self, n: usize) {
+        if UPLOAD_IS_READ.load(Ordering::Relaxed) {
+            self.upload(n).await
+        } else {
+            self.download(n).await
+        }
+    }
+
+    /// Waits before the bytes which came from the other side are written in the socket
+    pub async fn write(&self, n: usize) {
+        if UPLOAD_IS_READ.load(Ordering::Relaxed) {
+            self.download(n).await
+        } else {
+            self.upload(n).await
+        }
+    }
+
+    async fn upload(&self, n: usize) {
+        self.connection.upload.take(n).await;
+        self.listener.upload.take(n).await;
+        GLOBAL.upload.take(n).await;
+    }
+
+    async fn download(&self, n: usize) {
+        self.connection.download.take(n).await;
+        self.listener.download.take(n).await;
+        GLOBAL.download.take(n).await;
+    }
+}
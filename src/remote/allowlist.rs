@@ -0,0 +1,252 @@
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+use ipnet::IpNet;
+use serde::Deserialize;
+use tokio::net::lookup_host;
+
+use crate::request::DialStatus;
+
+/// The hosts which a rule matches
+#[derive(Debug, Clone)]
+enum HostPattern {
+    /// Every host
+    Any,
+    /// An IP range
+    Network(IpNet),
+    /// A hostname. If it starts with *. it matches the subdomains.
+    Hostname(String),
+}
+
+/// A rule which allows some destinations to be dialed dynamically.
+/// It looks like `10.0.0.0/8`, `[2001:db8::/32]:443`, `*.example.com:8000-9000` or `*:22`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub struct DestinationRule {
+    host: HostPattern,
+    /// The inclusive range of allowed ports
+    ports: (u16, u16),
+}
+
+impl FromStr for DestinationRule {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        // Separate the host and port
+        let (host, ports) = if let Some(rest) = value.strip_prefix('[') {
+            let (host, rest) = rest.split_once(']').ok_or("missing ] in rule")?;
+            match rest {
+                "" => (host, None),
+                _ => (
+                    host,
+                    Some(rest.strip_prefix(':').ok_or("expected : after ]")?),
+                ),
+            }
+        } else if value.matches(':').count() > 1 {
+            (value, None) // IPv6 without port
+        } else {
+            match value.split_once(':') {
+                Some((host, ports)) => (host, Some(ports)),
+                None => (value, None),
+            }
+        };
+        // Parse each of them
+        let ports = match ports {
+            None | Some("*") => (0, u16::MAX),
+            Some(ports) => match ports.split_once('-') {
+                Some((start, end)) => (parse_port(start)?, parse_port(end)?),
+                None => {
+                    let port = parse_port(ports)?;
+                    (port, port)
+                }
+            },
+        };
+        let host = if host == "*" {
+            HostPattern::Any
+        } else if let Ok(network) = IpNet::from_str(host) {
+            HostPattern::Network(network)
+        } else if let Ok(address) = IpAddr::from_str(host) {
+            HostPattern::Network(IpNet::from(address))
+        } else if !host.is_empty()
+            && host
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.' || c == '*')
+        {
+            HostPattern::Hostname(host.to_ascii_lowercase())
+        } else {
+            return Err(format!("invalid host in rule: {host}"));
+        };
+        Ok(DestinationRule { host, ports })
+    }
+}
+
+impl TryFrom<String> for DestinationRule {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+fn parse_port(port: &str) -> Result<u16, String> {
+    port.parse()
+        .map_err(|_| format!("invalid port in rule: {port}"))
+}
+
+impl DestinationRule {
+    fn matches_port(&self, port: u16) -> bool {
+        self.ports.0 <= port && port <= self.ports.1
+    }
+
+    fn matches_hostname(&self, hostname: &str, port: u16) -> bool {
+        let host_matches = match &self.host {
+            HostPattern::Any => true,
+            HostPattern::Network(_) => false,
+            HostPattern::Hostname(pattern) => match pattern.strip_prefix("*.") {
+                Some(suffix) => hostname
+                    .strip_suffix(suffix)
+                    .is_some_and(|subdomain| subdomain.ends_with('.')),
+                None => hostname == pattern,
+            },
+        };
+        host_matches && self.matches_port(port)
+    }
+
+    fn matches_address(&self, address: SocketAddr) -> bool {
+        let host_matches = match &self.host {
+            HostPattern::Any => true,
+            HostPattern::Network(network) => network.contains(&address.ip()),
+            HostPattern::Hostname(_) => false,
+        };
+        host_matches && self.matches_port(address.port())
+    }
+}
+
+/// Checks a host:port destination against the rules and resolves it to the addresses which can be dialed.
+/// Hostnames are either allowed by a hostname rule, or each of their resolved addresses is checked
+/// against the IP rules. In the latter case, only the allowed addresses are returned.
+pub(crate) async fn resolve_allowed(
+    rules: &[DestinationRule],
+    destination: &str,
+) -> Result<Vec<SocketAddr>, DialStatus> {
+    let (host, port) = destination.rsplit_once(':').ok_or(DialStatus::Failed)?;
+    let port: u16 = port.parse().map_err(|_| DialStatus::Failed)?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    // IP addresses are simply checked
+    if let Ok(ip) = IpAddr::from_str(host) {
+        let address = SocketAddr::new(ip, port);
+        return match rules.iter().any(|rule| rule.matches_address(address)) {
+            true => Ok(vec![address]),
+            false => Err(DialStatus::Denied),
+        };
+    }
+    // Hostnames must be resolved
+    let hostname = host.trim_end_matches('.').to_ascii_lowercase();
+    let addresses: Vec<SocketAddr> = lookup_host((hostname.as_str(), port))
+        .await
+        .map_err(|_| DialStatus::Failed)?
+        .collect();
+    if rules
+        .iter()
+        .any(|rule| rule.matches_hostname(&hostname, port))
+    {
+        return Ok(addresses);
+    }
+    let addresses: Vec<SocketAddr> = addresses
+        .into_iter()
+        .filter(|address| rules.iter().any(|rule| rule.matches_address(*address)))
+        .collect();
+    match addresses.is_empty() {
+        true => Err(DialStatus::Denied),
+        false => Ok(addresses),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(value: &str) -> DestinationRule {
+        value.parse().unwrap()
+    }
+
+    fn address(value: &str) -> SocketAddr {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn ipv6_with_and_without_port() {
+        let any_port = rule("::1");
+        assert!(any_port.matches_address(address("[::1]:22")));
+        assert!(any_port.matches_address(address("[::1]:65535")));
+        let bracketed = rule("[::1]");
+        assert!(bracketed.matches_address(address("[::1]:80")));
+        let with_port = rule("[2001:db8::/32]:443");
+        assert!(with_port.matches_address(address("[2001:db8::5]:443")));
+        assert!(!with_port.matches_address(address("[2001:db8::5]:80")));
+        assert!(!with_port.matches_address(address("[2001:db9::5]:443")));
+    }
+
+    #[test]
+    fn wildcard_subdomains() {
+        let rule = rule("*.example.com:443");
+        assert!(rule.matches_hostname("api.example.com", 443));
+        assert!(rule.matches_hostname("a.b.example.com", 443));
+        assert!(!rule.matches_hostname("example.com", 443));
+        assert!(!rule.matches_hostname("badexample.com", 443));
+        assert!(!rule.matches_hostname("api.example.com", 80));
+    }
+
+    #[test]
+    fn port_ranges() {
+        let rule = rule("*:8000-9000");
+        assert!(rule.matches_address(address("192.0.2.1:8000")));
+        assert!(rule.matches_address(address("192.0.2.1:9000")));
+        assert!(!rule.matches_address(address("192.0.2.1:7999")));
+        assert!(!rule.matches_address(address("192.0.2.1:9001")));
+        assert!(rule.matches_hostname("anything.test", 8500));
+    }
+
+    #[test]
+    fn invalid_rules() {
+        for value in [
+            "",
+            "[::1",
+            "[::1]443",
+            "host:port",
+            "10.0.0.0/8:1-x",
+            "bad_host",
+        ] {
+            assert!(
+                value.parse::<DestinationRule>().is_err(),
+                "{value} should be invalid"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn denied_destinations() {
+        let rules = [rule("10.0.0.0/8"), rule("[::1]:22")];
+        assert_eq!(
+            resolve_allowed(&rules, "10.1.2.3:80").await,
+            Ok(vec![address("10.1.2.3:80")])
+        );
+        assert_eq!(
+            resolve_allowed(&rules, "192.0.2.1:80").await,
+            Err(DialStatus::Denied)
+        );
+        assert_eq!(
+            resolve_allowed(&rules, "[::1]:22").await,
+            Ok(vec![address("[::1]:22")])
+        );
+        assert_eq!(
+            resolve_allowed(&rules, "[::1]:23").await,
+            Err(DialStatus::Denied)
+        );
+        // The names are resolved before matching, and localhost is not in the rules
+        assert_eq!(
+            resolve_allowed(&rules, "localhost:80").await,
+            Err(DialStatus::Denied)
+        );
+    }
+}
@@ -0,0 +1,34 @@
+//! Exponential backoff of the reconnect loops.
+//!
+//! The delay starts at retry_interval and doubles after each failure up to retry_max_interval.
+//! Each delay is randomly shortened by up to half, so the websockets do not retry all at once.
+
+use std::time::Duration;
+
+use rand::Rng;
+
+use crate::config::tuning;
+
+pub(crate) struct Backoff {
+    next: Duration,
+}
+
+impl Backoff {
+    pub fn new() -> Backoff {
+        Backoff {
+            next: tuning().retry_interval,
+        }
+    }
+
+    /// Starts over from retry_interval. Called once a connection is established.
+    pub fn reset(&mut self) {
+        self.next = tuning().retry_interval;
+    }
+
+    /// Returns how long we should wait before the next attempt
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.next;
+        self.next = (self.next * 2).min(tuning().retry_max_interval);
+        delay.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+    }
+}
@@ -0,0 +1,114 @@
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::handshake::client::Response;
+use tokio_tungstenite::tungstenite::http::{header, HeaderMap, HeaderValue, StatusCode};
+use tokio_tungstenite::tungstenite::Error;
+use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+use url::Url;
+
+use crate::compression;
+
+use super::tls::TlsClient;
+
+/// Dialer holds everything needed to open a websocket to the local server.
+pub(crate) struct Dialer {
+    /// The address of the local server (or cloudflare) including the base path
+    pub cloudflare_server_address: Url,
+    /// The secret which is presented in each handshake
+    pub secret: Option<String>,
+    /// Used instead of the default TLS settings for wss:// addresses
+    pub tls: TlsClient,
+    /// Extra headers which are sent in each handshake
+    pub headers: HeaderMap,
+}
+
+impl Dialer {
+    /// Opens a websocket to the given endpoint of the local server, like "control"
+    pub async fn connect(
+        &self,
+        endpoint: &str,
+    ) -> Result<(WebSocketStream<MaybeTlsStream<TcpStream>>, Response), Error> {
+        // Create the handshake request
+        let mut request = self.endpoint_url(endpoint).as_str().into_client_request()?;
+        if let Some(secret) = &self.secret {
+            let value = HeaderValue::from_str(&format!("Bearer {secret}"))
+                .map_err(|err| Error::HttpFormat(err.into()))?;
+            request.headers_mut().insert(header::AUTHORIZATION, value);
+        }
+        // Only /connect answers this for now, the other endpoints ignore it
+        if let Some(algorithm) = compression::offer() {
+            request
+                .headers_mut()
+                .insert(compression::HEADER, HeaderValue::from_static(algorithm));
+        }
+        // Custom headers replace the headers with the same name
+        request.headers_mut().extend(self.headers.clone());
+        match request.uri().scheme_str() {
+            Some("wss") => self.tls.connect(request).await,
+            _ => connect_async(request).await,
+        }
+    }
+
+    /// Joins the endpoint to the path of the address. The query string is kept as is.
+    fn endpoint_url(&self, endpoint: &str) -> Url {
+        let mut url = self.cloudflare_server_address.clone();
+        url.path_segments_mut()
+            .expect("address is validated in config")
+            .pop_if_empty()
+            .push(endpoint);
+        url
+    }
+}
+
+/// Returns true if the local server has agreed to compress the data of the websocket
+pub(crate) fn is_compressed(response: &Response) -> bool {
+    let header = response.headers().get(compression::HEADER);
+    compression::negotiate(header.and_then(|value| value.to_str().ok()))
+}
+
+/// Returns true if dialing again cannot fix the error, like a bad address or a wrong secret.
+/// Everything else, like a refused connection or a 5xx of Cloudflare, might be fixed by retrying.
+pub(crate) fn is_permanent(err: &Error) -> bool {
+    match err {
+        Error::Url(_) | Error::HttpFormat(_) => true,
+        Error::Http(response) => matches!(
+            response.status(),
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN | StatusCode::NOT_FOUND
+        ),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::remote::tls::TlsOptions;
+
+    fn endpoint(address: &str, endpoint: &str) -> String {
+        let dialer = Dialer {
+            cloudflare_server_address: Url::parse(address).unwrap(),
+            secret: None,
+            tls: TlsClient::new(TlsOptions::default()).unwrap(),
+            headers: HeaderMap::new(),
+        };
+        dialer.endpoint_url(endpoint).to_string()
+    }
+
+    #[test]
+    fn endpoint_urls() {
+        assert_eq!(endpoint("ws://h/", "control"), "ws://h/control");
+        assert_eq!(
+            endpoint("ws://h/api/v2", "connect"),
+            "ws://h/api/v2/connect"
+        );
+        // The query string of the address is kept for every endpoint
+        assert_eq!(
+            endpoint("wss://h/prefix/api/v2?a=b", "mux"),
+            "wss://h/prefix/api/v2/mux?a=b"
+        );
+        assert_eq!(
+            endpoint("ws://h/prefix/?a=b", "control"),
+            "ws://h/prefix/control?a=b"
+        );
+    }
+}
@@ -1,59 +1,219 @@
-use std::{process::exit, str::FromStr, time::Duration};
-
-use futures::StreamExt;
+use futures::{SinkExt, StreamExt};
 use log::{debug, error, info, warn};
-use tokio_tungstenite::{connect_async, tungstenite::protocol::Message};
-use uuid::Uuid;
+use tokio::sync::Semaphore;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::{CloseFrame, Message};
+
+use crate::admin;
+use crate::config::{tuning, ServerConfig};
+use crate::metrics;
+use crate::ratelimit;
+use crate::request::{ConnectionRequest, DialReport};
+use crate::shutdown;
 
+use backoff::Backoff;
+
+pub(crate) mod allowlist;
+mod backoff;
+mod dialer;
+mod mux;
+mod pool;
 mod proxy;
+pub(crate) mod tls;
+mod udp;
+
+/// The close frame which is sent in the websockets when we are shutting down
+pub(crate) fn going_away() -> Message {
+    Message::Close(Some(CloseFrame {
+        code: CloseCode::Away,
+        reason: "shutting down".into(),
+    }))
+}
 
-pub async fn start_remote_controller(cloudflare_server_address: String, forward_address: String) {
+/// Runs the remote server. Only returns if the local server cannot be used with our config.
+pub async fn start_remote_controller(config: ServerConfig) -> Result<(), String> {
+    let ServerConfig {
+        cloudflare_server_address,
+        forward_addresses,
+        allowed_destinations,
+        secret,
+        mux_connections,
+        pool_min,
+        pool_max,
+        tls,
+        headers,
+        max_dials,
+        proxy_protocol,
+        metrics_listen_address,
+        admin_listen_address,
+    } = config;
+    // The sockets of the remote server read the downloads from the targets
+    ratelimit::init(false);
+    let tls = tls::TlsClient::new(tls).map_err(|err| format!("Cannot set up TLS: {err}"))?;
     // Leak the addresses because memory leaks are cool.
     // We need these variables throughout the whole program and threads. So we can simply leak it
-    let cloudflare_server_address: &'static str = Box::leak(Box::new(cloudflare_server_address));
-    let forward_address: &'static str = Box::leak(Box::new(forward_address));
-    let controller_address = format!("{}/control", cloudflare_server_address);
+    let dialer: &'static dialer::Dialer = Box::leak(Box::new(dialer::Dialer {
+        cloudflare_server_address,
+        secret,
+        tls,
+        headers: headers
+            .into_iter()
+            .map(|header| (header.name, header.value))
+            .collect(),
+    }));
+    let targets: &'static proxy::Targets = Box::leak(Box::new(proxy::Targets {
+        forward_addresses: forward_addresses
+            .into_iter()
+            .map(|mapping| (mapping.name, mapping.address))
+            .collect(),
+        allowed_destinations,
+        dial_slots: (max_dials > 0).then(|| Semaphore::new(max_dials)),
+        proxy_protocol,
+    }));
+    // The metrics are served on their own address
+    if let Some(address) = metrics_listen_address {
+        let listener = metrics::bind(&address).await?;
+        tokio::task::spawn(metrics::serve(listener, |_: &mut String| {}));
+    }
+    // So is the admin API
+    if let Some(address) = admin_listen_address {
+        let listener = admin::bind(&address).await?;
+        tokio::task::spawn(admin::serve(listener));
+    }
+    // Open the multiplexed websockets if requested. They work alongside the controller.
+    for _ in 0..mux_connections {
+        tokio::task::spawn(mux::run_mux_session(dialer, targets));
+    }
+    // Also keep some websockets open ahead of time to skip the handshake on new connections
+    if pool_max > 0 {
+        tokio::task::spawn(pool::run_pool(dialer, targets, pool_min, pool_max));
+    }
     // Create an infinite loop of retries because cloudflare WS connection sometimes disconnects
+    let mut backoff = Backoff::new();
     loop {
+        // Do not come back once we are shutting down. The other tasks are still finishing.
+        if shutdown::is_draining() {
+            return std::future::pending().await;
+        }
         // First thing we should do is starting a websocket client as the controller of the
         // local computer.
-        let (mut controller_websocket, _) = connect_async(&controller_address)
-            .await
-            .expect("cannot parse the cloudflare_server_address");
+        let mut controller_websocket = match dialer.connect("control").await {
+            Ok((websocket, _)) => websocket,
+            Err(err) if dialer::is_permanent(&err) => {
+                return Err(format!("Cannot connect the controller: {err}"));
+            }
+            Err(err) => {
+                warn!("Cannot connect the controller: {err}");
+                retry_controller(&mut backoff).await;
+                continue;
+            }
+        };
         debug!("Controller connected");
         // Get the ack message
         match controller_websocket.next().await {
+            Some(Ok(Message::Text(msg))) if msg == "ack" => {}
+            // Something else is listening there, so retrying does not help
             Some(Ok(Message::Text(msg))) => {
-                if msg != "ack" {
-                    error!("First packet is not ack: {msg}");
-                    exit(1);
-                }
+                return Err(format!("First packet is not ack: {msg}"));
             }
             other => {
-                error!("First packet is not ack: {:?}", other);
-                exit(1);
+                warn!("Controller did not receive the ack: {:?}", other);
+                retry_controller(&mut backoff).await;
+                continue;
             }
         }
         // The connected websocket is only used to read the commands
         info!("Controller connection established");
+        backoff.reset();
+        metrics::CONTROLLER_CONNECTIONS.inc();
+        admin::set_controller_connected(true);
+        let _websocket = shutdown::WEBSOCKETS.track();
+        let mut told_peer = false;
+        // Ping the local server every now and then. If it does not say anything back, the link is dead.
+        let mut heartbeat = tokio::time::interval(tuning().heartbeat_interval);
+        let silence = tokio::time::sleep(tuning().heartbeat_timeout);
+        tokio::pin!(silence);
         'controller_reader_loop: loop {
             // Read the command from websocket
-            match controller_websocket.next().await {
+            let command = tokio::select! {
+                command = controller_websocket.next() => command,
+                _ = heartbeat.tick() => {
+                    if let Err(err) = controller_websocket.send(Message::Ping(Vec::new())).await {
+                        warn!("Cannot ping the controller: {err}");
+                        break 'controller_reader_loop;
+                    }
+                    continue;
+                }
+                _ = &mut silence => {
+                    warn!(
+                        "Local server did not respond in {}s",
+                        tuning().heartbeat_timeout.as_secs()
+                    );
+                    break 'controller_reader_loop;
+                }
+                // Tell the local server that no more connections are accepted
+                _ = shutdown::draining(), if !told_peer => {
+                    told_peer = true;
+                    let message = Message::Text(shutdown::SHUTDOWN_MESSAGE.to_owned());
+                    let _ = controller_websocket.send(message).await;
+                    continue;
+                }
+                _ = shutdown::closing() => {
+                    let _ = controller_websocket.send(going_away()).await;
+                    break 'controller_reader_loop;
+                }
+            };
+            // Anything which arrives, including the pongs, means that the link is alive
+            silence
+                .as_mut()
+                .reset(tokio::time::Instant::now() + tuning().heartbeat_timeout);
+            match command {
+                Some(Ok(Message::Close(close_code))) => {
+                    info!("Controller closed with {:?}", close_code);
+                    break 'controller_reader_loop;
+                }
                 Some(Ok(command)) => {
                     if let Message::Text(command) = command {
+                        if command == shutdown::SHUTDOWN_MESSAGE {
+                            info!("Local server is shutting down");
+                            continue;
+                        }
+                        if shutdown::is_draining() {
+                            warn!("Ignoring a connection request because we are shutting down");
+                            continue;
+                        }
                         // The only message type supported right now is simply the connection request
-                        // that sends the UUID of the connection in the socket!
-                        let requested_uuid = Uuid::from_str(&command);
-                        if let Err(err) = requested_uuid {
+                        // that sends the UUID and the service of the connection in the socket!
+                        let request = ConnectionRequest::decode(command.as_bytes());
+                        if let Err(err) = request {
                             warn!("Invalid packet received from local server: {:?}", err);
                             continue;
                         }
-                        let requested_uuid = requested_uuid.unwrap();
+                        let request = request.unwrap();
+                        // The pool could have taken this connection, so it should grow
+                        pool::count_demand();
+                        // Reject the request right away if too many targets are being dialed,
+                        // so the local server can answer its client instead of waiting for the websocket
+                        let slot = match proxy::acquire_dial_slot(targets, request.id) {
+                            Ok(slot) => slot,
+                            Err(status) => {
+                                let report = DialReport {
+                                    id: request.id,
+                                    status,
+                                };
+                                if let Err(err) = controller_websocket.send(Message::Text(report.encode())).await {
+                                    warn!("Cannot send the dial report to the controller: {err}");
+                                    break 'controller_reader_loop;
+                                }
+                                continue;
+                            }
+                        };
                         // Create a task that handles the connection
                         tokio::task::spawn(proxy::handle_new_connection_request(
-                            requested_uuid,
-                            cloudflare_server_address,
-                            forward_address,
+                            request,
+                            dialer,
+                            targets,
+                            slot,
                         ));
                     }
                 }
@@ -64,8 +224,21 @@ pub async fn start_remote_controller(cloudflare_server_address: String, forward_
             }
         }
         // Retry...
+        admin::set_controller_connected(false);
         drop(controller_websocket);
-        tokio::time::sleep(Duration::from_secs(5)).await;
-        info!("Retrying to connect the controller...");
+        if shutdown::is_draining() {
+            continue;
+        }
+        retry_controller(&mut backoff).await;
     }
 }
+
+/// Waits before dialing the controller again
+async fn retry_controller(backoff: &mut Backoff) {
+    let delay = backoff.next_delay();
+    info!(
+        "Retrying to connect the controller in {:.1}s...",
+        delay.as_secs_f64()
+    );
+    tokio::time::sleep(delay).await;
+}
@@ -0,0 +1,129 @@
+use futures::{SinkExt, StreamExt};
+use log::{debug, info, warn};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio_tungstenite::tungstenite::http::StatusCode;
+use tokio_tungstenite::tungstenite::{Error, Message};
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+use crate::admin;
+use crate::config::tuning;
+use crate::mux::{self, Frame, MuxSession};
+use crate::request::DialStatus;
+use crate::shutdown;
+
+use super::backoff::Backoff;
+use super::dialer::Dialer;
+use super::going_away;
+use super::proxy::{self, Targets};
+
+/// Keeps a multiplexed websocket open to the local server and serves the streams in it.
+/// Returns if the local server does not support multiplexing.
+pub(crate) async fn run_mux_session(dialer: &'static Dialer, targets: &'static Targets) {
+    let mut backoff = Backoff::new();
+    // Do not come back once we are shutting down
+    while !shutdown::is_draining() {
+        match dialer.connect("mux").await {
+            Ok((websocket, _)) => {
+                info!("Mux session established");
+                backoff.reset();
+                serve_session(websocket, targets).await;
+                warn!("Mux session closed");
+            }
+            Err(Error::Http(response)) if response.status() == StatusCode::NOT_FOUND => {
+                warn!(
+                    "Local server does not support multiplexing, using a websocket per connection"
+                );
+                return;
+            }
+            Err(err) => warn!("cannot connect to /mux websocket: {:?}", err),
+        }
+        // Retry...
+        let delay = backoff.next_delay();
+        info!(
+            "Retrying to connect the mux session in {:.1}s...",
+            delay.as_secs_f64()
+        );
+        tokio::time::sleep(delay).await;
+    }
+}
+
+async fn serve_session(
+    websocket: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    targets: &'static Targets,
+) {
+    let _websocket = shutdown::WEBSOCKETS.track();
+    let (session, mut frames) = MuxSession::new();
+    let (mut websocket_tx, mut websocket_rx) = websocket.split();
+    // Write the frames in the websocket until we are shutting down
+    let mut writer = tokio::spawn(async move {
+        loop {
+            let frame = tokio::select! {
+                frame = frames.recv() => match frame {
+                    Some(frame) => frame,
+                    None => return,
+                },
+                _ = shutdown::closing() => {
+                    let _ = websocket_tx.send(going_away()).await;
+                    return;
+                }
+            };
+            if let Err(err) = websocket_tx.send(Message::Binary(frame.encode())).await {
+                debug!("Writer of mux session returned error: {:?}", err);
+                return;
+            }
+        }
+    });
+    // Read the frames and open the requested streams
+    loop {
+        tokio::select! {
+            _ = (&mut writer) => break,
+            msg = websocket_rx.next() => {
+                let data = match msg {
+                    Some(Ok(Message::Binary(data))) => data,
+                    Some(Ok(_)) => continue, // We dont care about other types of messages
+                    other => {
+                        debug!("Reader of mux session closed: {:?}", other);
+                        break;
+                    }
+                };
+                let frame = match mux::decode_or_warn(data) {
+                    Some(frame) => frame,
+                    None => continue,
+                };
+                if let Some(Frame::Open { stream_id, request }) = session.dispatch(frame).await {
+                    let connection_id = request.id;
+                    if shutdown::is_draining() {
+                        warn!("Rejecting connection {connection_id} because we are shutting down");
+                        session.send_dialed(stream_id, DialStatus::Failed).await;
+                        session.close_stream(stream_id, true).await;
+                        continue;
+                    }
+                    info!("Accepted connection {connection_id} in mux stream {stream_id}");
+                    // Attach the stream right now so no data is lost while we are dialing
+                    let (socket_sender, socket_receiver) = mpsc::channel(tuning().socket_queue_length);
+                    let (websocket_sender, websocket_receiver) = mpsc::channel(tuning().socket_queue_length);
+                    session.attach_stream(stream_id, socket_receiver, websocket_sender, None);
+                    let session = session.clone();
+                    tokio::spawn(async move {
+                        let connection = admin::register(connection_id, request.service.clone(), request.protocol, request.client_address);
+                        // Dropping the pipes will close the stream if we cannot dial
+                        let target = proxy::dial_target(&request, &connection, targets, None).await;
+                        let status = match &target {
+                            Ok(_) => DialStatus::Connected,
+                            Err(status) => *status,
+                        };
+                        session.send_dialed(stream_id, status).await;
+                        if let Ok(target) = target {
+                            let proxy = proxy::proxy_target(target, &connection, socket_sender, websocket_receiver);
+                            connection.unless_killed(proxy).await;
+                        }
+                        info!("Connection {connection_id} finished");
+                    });
+                }
+            }
+        }
+    }
+    writer.abort();
+    session.close();
+}
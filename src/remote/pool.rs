@@ -0,0 +1,161 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use futures::{SinkExt, StreamExt};
+use log::{debug, info, warn};
+use tokio::net::TcpStream;
+use tokio::sync::Notify;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+use crate::config::tuning;
+use crate::request::ConnectionRequest;
+use crate::shutdown;
+
+use super::dialer::{self, Dialer};
+use super::going_away;
+use super::proxy::{self, Targets};
+
+/// The message which we send instead of the UUID to tell the local server that the websocket is idle
+const POOL_GREETING: &str = "pool";
+/// How often the pool size is adjusted to the demand
+const POOL_ADJUST_INTERVAL: Duration = Duration::from_secs(10);
+/// How often idle websockets are pinged so proxies in the middle do not close them
+const IDLE_PING_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How many connections arrived since the last adjustment of the pool.
+/// It counts the ones which took a pooled websocket and the ones which came through the controller,
+/// because the pool was empty or it was not open yet.
+static DEMAND: AtomicUsize = AtomicUsize::new(0);
+
+/// A pool of websockets which are opened ahead of time
+struct Pool {
+    /// The least number of idle websockets
+    min: usize,
+    /// The most number of idle websockets
+    max: usize,
+    /// Websockets which are open (or being opened) and are not assigned yet
+    idle: AtomicUsize,
+    /// How many idle websockets we want to have right now
+    target: AtomicUsize,
+    /// Notified whenever an idle websocket is gone
+    refill: Notify,
+}
+
+/// Keeps between min and max idle websockets open to the local server.
+/// The pool grows toward max when connections arrive faster and shrinks back to min when they don't.
+pub(crate) async fn run_pool(
+    dialer: &'static Dialer,
+    targets: &'static Targets,
+    min: usize,
+    max: usize,
+) {
+    let pool: &'static Pool = Box::leak(Box::new(Pool {
+        min,
+        max,
+        idle: AtomicUsize::new(0),
+        target: AtomicUsize::new(min),
+        refill: Notify::new(),
+    }));
+    info!("Keeping between {min} and {max} idle websockets");
+    let mut adjust = tokio::time::interval(POOL_ADJUST_INTERVAL);
+    loop {
+        // Open websockets until we reach the target
+        while pool.idle.load(Ordering::Relaxed) < pool.target.load(Ordering::Relaxed) {
+            pool.idle.fetch_add(1, Ordering::Relaxed);
+            tokio::task::spawn(pooled_websocket(pool, dialer, targets));
+        }
+        tokio::select! {
+            _ = adjust.tick() => {
+                // Size the pool by the number of connections in the last interval
+                let demand = DEMAND.swap(0, Ordering::Relaxed);
+                let target = demand.clamp(pool.min, pool.max);
+                if pool.target.swap(target, Ordering::Relaxed) != target {
+                    debug!("Pool target size is now {target}");
+                }
+            }
+            _ = pool.refill.notified() => {}
+            // The idle websockets close themselves, so just stop refilling
+            _ = shutdown::draining() => return,
+        }
+    }
+}
+
+/// Counts a new connection in the demand which sizes the pool
+pub(crate) fn count_demand() {
+    DEMAND.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Opens an idle websocket, waits for a connection and proxies it
+async fn pooled_websocket(pool: &Pool, dialer: &Dialer, targets: &Targets) {
+    match wait_for_assignment(dialer).await {
+        Some((websocket, request, compressed)) => {
+            pool.idle.fetch_sub(1, Ordering::Relaxed);
+            count_demand();
+            pool.refill.notify_one();
+            info!("Accepted connection {} on a pooled websocket", request.id);
+            proxy::proxy_websocket(websocket, request, targets, compressed, None).await;
+        }
+        None => {
+            // Do not hammer the local server if it's down
+            tokio::time::sleep(tuning().retry_interval).await;
+            pool.idle.fetch_sub(1, Ordering::Relaxed);
+            pool.refill.notify_one();
+        }
+    }
+}
+
+/// Opens a websocket to /connect and waits until the local server assigns a connection to it
+/// Also returns whether the data of the websocket is compressed.
+async fn wait_for_assignment(
+    dialer: &Dialer,
+) -> Option<(
+    WebSocketStream<MaybeTlsStream<TcpStream>>,
+    ConnectionRequest,
+    bool,
+)> {
+    let (mut websocket, compressed) = match dialer.connect("connect").await {
+        Ok((websocket, response)) => (websocket, dialer::is_compressed(&response)),
+        Err(err) => {
+            warn!("cannot connect to /connect websocket of pool: {:?}", err);
+            return None;
+        }
+    };
+    if let Err(err) = websocket
+        .send(Message::Text(POOL_GREETING.to_owned()))
+        .await
+    {
+        warn!("cannot send the pool greeting: {:?}", err);
+        return None;
+    }
+    let _websocket = shutdown::WEBSOCKETS.track();
+    let mut ping = tokio::time::interval(IDLE_PING_INTERVAL);
+    ping.tick().await; // the first tick is immediate
+    loop {
+        tokio::select! {
+            _ = ping.tick() => {
+                if websocket.send(Message::Ping(Vec::new())).await.is_err() {
+                    return None;
+                }
+            }
+            msg = websocket.next() => match msg {
+                Some(Ok(Message::Text(command))) => match ConnectionRequest::decode(command.as_bytes()) {
+                    Ok(request) => return Some((websocket, request, compressed)),
+                    Err(err) => {
+                        warn!("Invalid assignment received from local server: {:?}", err);
+                        return None;
+                    }
+                },
+                Some(Ok(Message::Ping(_))) | Some(Ok(Message::Pong(_))) => {}
+                other => {
+                    debug!("Idle websocket closed: {:?}", other);
+                    return None;
+                }
+            },
+            _ = shutdown::draining() => {
+                let _ = websocket.send(going_away()).await;
+                return None;
+            }
+        }
+    }
+}
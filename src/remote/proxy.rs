@@ -1,28 +1,69 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::Instant;
+
 use futures::{SinkExt, StreamExt};
 use log::{debug, info, warn};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpStream,
-    sync::mpsc,
+    io::AsyncWriteExt,
+    net::{lookup_host, TcpStream, UdpSocket},
+    sync::{mpsc, Semaphore, SemaphorePermit},
 };
-use tokio_tungstenite::{connect_async, tungstenite::Message};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
 use uuid::Uuid;
 
-/// How many packets can be queued in the socket queue
-const SOCKET_QUEUE_LENGTH: usize = 32;
-/// How big is our read buffer size
-const READ_BUFFER_SIZE: usize = 32 * 1024;
+use crate::admin::{self, Connection};
+use crate::config::tuning;
+use crate::request::{ConnectionRequest, DialStatus, Protocol};
+use crate::{compression, metrics, proxy_protocol, shutdown, tcp};
+
+use super::allowlist::{self, DestinationRule};
+use super::dialer::{self, Dialer};
+use super::going_away;
+use super::udp;
+
+/// Decides where the connections should be forwarded to
+pub(crate) struct Targets {
+    /// Where the connections of each service should be forwarded to
+    pub forward_addresses: HashMap<String, String>,
+    /// Which destinations can be requested dynamically, for example by SOCKS clients
+    pub allowed_destinations: Vec<DestinationRule>,
+    /// If set, only this many targets can be dialed at once
+    pub dial_slots: Option<Semaphore>,
+    /// If set, this PROXY protocol header is sent to the forward addresses
+    pub proxy_protocol: Option<proxy_protocol::Version>,
+}
+
+/// A socket which is connected to the target of a connection
+pub(crate) enum Target {
+    Tcp(TcpStream),
+    Udp(UdpSocket),
+}
+
+impl Target {
+    fn peer_addr(&self) -> std::io::Result<SocketAddr> {
+        match self {
+            Target::Tcp(socket) => socket.peer_addr(),
+            Target::Udp(socket) => socket.peer_addr(),
+        }
+    }
+}
 
 /// Handles a new connection request.
-/// At first, creates a websocket connection
+/// At first, creates a websocket connection.
+/// The slot of max_dials is taken by the controller, and it's held from the handshake of the
+/// websocket until the target is dialed, so a burst of requests does not open a websocket for each of them.
 pub(crate) async fn handle_new_connection_request(
-    connection_id: Uuid,
-    cloudflare_server_address: &str,
-    forward_address: &str,
+    request: ConnectionRequest,
+    dialer: &Dialer,
+    targets: &Targets,
+    slot: Option<SemaphorePermit<'_>>,
 ) {
+    let connection_id = request.id;
     info!("Accepted connection {connection_id}");
     // At first create the websocket
-    let websocket = connect_async(format!("{}/connect", cloudflare_server_address)).await;
+    let websocket = dialer.connect("connect").await;
     if let Err(err) = websocket {
         warn!(
             "cannot connect to /connect websocket {connection_id}: {:?}",
@@ -30,7 +71,8 @@ pub(crate) async fn handle_new_connection_request(
         );
         return;
     }
-    let (mut websocket, _) = websocket.unwrap();
+    let (mut websocket, response) = websocket.unwrap();
+    let compressed = dialer::is_compressed(&response);
     // Send the uuid in the socket
     if let Err(err) = websocket
         .send(Message::Text(connection_id.to_string()))
@@ -39,28 +81,61 @@ pub(crate) async fn handle_new_connection_request(
         warn!("cannot send id in websocket {connection_id}: {:?}", err);
         return;
     }
-    let (mut websocket_tx, mut websocket_rx) = websocket.split();
-    // Now create the TCP socket
-    let tcp_socket = TcpStream::connect(forward_address).await;
-    if let Err(err) = tcp_socket {
-        warn!(
-            "cannot connect to TCP socket of connection {connection_id}: {:?}",
-            err
-        );
+    proxy_websocket(websocket, request, targets, compressed, slot).await;
+}
+
+/// Dials the target and proxies the data between it and a websocket
+/// which is already associated with a connection.
+/// If compressed is set, the data messages are compressed as negotiated in the handshake.
+/// The slot of max_dials is released once the target is dialed.
+pub(crate) async fn proxy_websocket(
+    mut websocket: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    request: ConnectionRequest,
+    targets: &Targets,
+    compressed: bool,
+    slot: Option<SemaphorePermit<'_>>,
+) {
+    let connection_id = request.id;
+    let connection = admin::register(connection_id, request.service.clone(), request.protocol, request.client_address);
+    // Dial the target and report the result before any data
+    let target = dial_target(&request, &connection, targets, slot).await;
+    let status = match &target {
+        Ok(_) => DialStatus::Connected,
+        Err(status) => *status,
+    };
+    if let Err(err) = websocket.send(Message::Text(status.encode())).await {
+        warn!("cannot send dial status in websocket {connection_id}: {:?}", err);
         return;
     }
-    let (mut tcp_socket_rx, mut tcp_socket_tx) = tcp_socket.unwrap().into_split();
+    let target = match target {
+        Ok(target) => target,
+        Err(_) => return,
+    };
+    let _websocket = shutdown::WEBSOCKETS.track();
+    let (mut websocket_tx, mut websocket_rx) = websocket.split();
     // Create the pipes in order to proxy the data
-    let (socket_sender, mut socket_receiver) = mpsc::channel(SOCKET_QUEUE_LENGTH);
-    let (websocket_sender, mut websocket_receiver) = mpsc::channel(SOCKET_QUEUE_LENGTH);
-    // Create four tasks to...
+    let (socket_sender, mut socket_receiver) = mpsc::channel(tuning().socket_queue_length);
+    let (websocket_sender, websocket_receiver) = mpsc::channel(tuning().socket_queue_length);
+    // Create two tasks to...
     // 1. Read data from websocket
     let mut websocket_reader = tokio::task::spawn(async move {
         loop {
             match websocket_rx.next().await {
                 Some(Ok(msg)) => {
                     if let Message::Binary(data) = msg {
-                        websocket_sender.send(data).await.unwrap();
+                        let data = match compressed {
+                            true => match compression::decompress(data) {
+                                Ok(data) => data,
+                                Err(err) => {
+                                    warn!("Invalid compressed data in websocket {connection_id}: {err}");
+                                    break;
+                                }
+                            },
+                            false => data,
+                        };
+                        if websocket_sender.send(data).await.is_err() {
+                            break; // target closed
+                        }
                     }
                     // We dont care about other types of messages
                 }
@@ -71,50 +146,164 @@ pub(crate) async fn handle_new_connection_request(
             }
         }
     });
-    // 2. Read data from socket
-    let mut socket_reader = tokio::task::spawn(async move {
-        let mut buffer = [0u8; READ_BUFFER_SIZE];
+    // 2. Write data to websocket
+    let mut websocket_writer = tokio::task::spawn(async move {
         loop {
-            match tcp_socket_rx.read(&mut buffer).await {
-                Ok(n) => {
-                    socket_sender.send(buffer[..n].to_owned()).await.unwrap();
-                }
-                Err(err) => {
-                    debug!("Reader socket {connection_id} closed: {:?}", err);
-                    break;
+            let data = tokio::select! {
+                data = socket_receiver.recv() => match data {
+                    Some(data) => data,
+                    None => break,
+                },
+                _ = shutdown::closing() => {
+                    let _ = websocket_tx.send(going_away()).await;
+                    return;
                 }
-            }
-        }
-    });
-    // 3. Write data to websocket
-    let mut websocket_writer = tokio::task::spawn(async move {
-        while let Some(data) = socket_receiver.recv().await {
+            };
+            let data = if compressed {
+                compression::compress(data)
+            } else {
+                data
+            };
             if let Err(err) = websocket_tx.send(Message::Binary(data)).await {
                 debug!("Writer websocket {connection_id} returned error: {:?}", err);
-                break;
-            }
-        }
-    });
-    // 4. Write data to socket
-    let mut tcp_socket_writer = tokio::task::spawn(async move {
-        while let Some(data) = websocket_receiver.recv().await {
-            if let Err(err) = tcp_socket_tx.write(&data).await {
-                debug!("Writer socket {connection_id} returned error: {:?}", err);
-                break;
+                return;
             }
         }
+        let _ = websocket_tx.close().await;
     });
-    // Wait until one of these tasks return, and then abort all of them
+    // Wait until one of the websocket tasks or the target return, and then abort all of them
     tokio::select! {
         _ = (&mut websocket_reader) => {},
-        _ = (&mut socket_reader) => {},
         _ = (&mut websocket_writer) => {},
-        _ = (&mut tcp_socket_writer) => {},
+        finished = connection.unless_killed(proxy_target(target, &connection, socket_sender, websocket_receiver)) => {
+            // Whatever the target has sent must be flushed before closing the websocket
+            if finished.is_some() {
+                let _ = (&mut websocket_writer).await;
+            }
+        },
     };
     // Abort everything
     websocket_reader.abort();
-    socket_reader.abort();
     websocket_writer.abort();
-    tcp_socket_writer.abort();
     info!("Connection {connection_id} finished");
 }
+
+/// Takes a slot of max_dials which is held until the returned permit is dropped.
+/// Fails right away instead of queueing the dials. The caller must report the failure to the
+/// local server, so the client is answered right away.
+pub(crate) fn acquire_dial_slot(targets: &Targets, connection_id: Uuid) -> Result<Option<SemaphorePermit<'_>>, DialStatus> {
+    match &targets.dial_slots {
+        Some(slots) => match slots.try_acquire() {
+            Ok(slot) => Ok(Some(slot)),
+            Err(_) => {
+                warn!("Connection {connection_id} is rejected because too many targets are being dialed");
+                metrics::dial_rejected();
+                Err(DialStatus::Failed)
+            }
+        },
+        None => Ok(None),
+    }
+}
+
+/// Dials the target of a connection with its protocol.
+/// The target is either the address of its service or its requested destination if it's allowed.
+/// If the caller has not taken a slot of max_dials, it's taken here.
+pub(crate) async fn dial_target(
+    request: &ConnectionRequest,
+    connection: &Connection,
+    targets: &Targets,
+    slot: Option<SemaphorePermit<'_>>,
+) -> Result<Target, DialStatus> {
+    let _slot = match slot {
+        Some(slot) => Some(slot),
+        None => acquire_dial_slot(targets, request.id)?,
+    };
+    let started = Instant::now();
+    let target = dial(request, targets).await;
+    metrics::CONNECT_DURATION.observe(started.elapsed());
+    match &target {
+        Ok(target) => {
+            if let Ok(address) = target.peer_addr() {
+                connection.set_target(address.to_string());
+            }
+            connection.set_joined();
+        }
+        Err(status) => metrics::dial_failed(*status),
+    }
+    target
+}
+
+async fn dial(request: &ConnectionRequest, targets: &Targets) -> Result<Target, DialStatus> {
+    let connection_id = request.id;
+    // Find out which addresses we should dial
+    let addresses = match &request.destination {
+        Some(destination) => {
+            allowlist::resolve_allowed(&targets.allowed_destinations, destination)
+                .await
+                .inspect_err(|status| {
+                    warn!("Connection {connection_id} to {destination} is {:?}", status)
+                })?
+        }
+        None => {
+            let forward_address = match targets.forward_addresses.get(&request.service) {
+                Some(forward_address) => forward_address,
+                None => {
+                    warn!(
+                        "Connection {connection_id} requested unknown service {}",
+                        request.service
+                    );
+                    return Err(DialStatus::Failed);
+                }
+            };
+            lookup_host(forward_address)
+                .await
+                .map_err(|err| {
+                    warn!("cannot resolve {forward_address} of connection {connection_id}: {err}");
+                    DialStatus::Failed
+                })?
+                .collect()
+        }
+    };
+    // Now connect to them
+    let target = match request.protocol {
+        Protocol::Tcp => TcpStream::connect(addresses.as_slice()).await.map(Target::Tcp),
+        Protocol::Udp => udp::connect(&addresses).await.map(Target::Udp),
+    };
+    let mut target = target.map_err(|err| {
+        warn!(
+            "cannot connect to target of connection {connection_id}: {:?}",
+            err
+        );
+        DialStatus::Failed
+    })?;
+    // Tell the forward address who the client is. The requested destinations are usually not ours, so they do not get it.
+    if let (Target::Tcp(tcp_socket), Some(version), None) =
+        (&mut target, targets.proxy_protocol, &request.destination)
+    {
+        let header = proxy_protocol::encode(version, request.client_address, request.listener_address);
+        if let Err(err) = tcp_socket.write_all(&header).await {
+            warn!("cannot send the PROXY header of connection {connection_id}: {err}");
+            return Err(DialStatus::Failed);
+        }
+    }
+    Ok(target)
+}
+
+/// Proxies the data between a target and the pipes of a websocket or a mux stream
+pub(crate) async fn proxy_target(
+    target: Target,
+    connection: &Connection,
+    socket_sender: mpsc::Sender<Vec<u8>>,
+    websocket_receiver: mpsc::Receiver<Vec<u8>>,
+) {
+    match target {
+        Target::Tcp(tcp_socket) => {
+            // UDP sessions are not counted because they only end when they are idle
+            let _tracked = shutdown::CONNECTIONS.track();
+            tcp::proxy_tcp(tcp_socket, connection, socket_sender, websocket_receiver).await
+        }
+        Target::Udp(udp_socket) => {
+            udp::proxy_udp(udp_socket, connection, socket_sender, websocket_receiver).await
+        }
+    }
+}
@@ -0,0 +1,192 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{ring, verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme};
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+use tokio_tungstenite::tungstenite::error::{TlsError, UrlError};
+use tokio_tungstenite::tungstenite::handshake::client::{Request, Response};
+use tokio_tungstenite::tungstenite::Error;
+use tokio_tungstenite::{client_async, MaybeTlsStream, WebSocketStream};
+
+/// How the remote server does TLS when the address of the local server is wss://
+#[derive(Debug, Default)]
+pub struct TlsOptions {
+    /// PEM file of the CAs which are trusted instead of the public ones
+    pub ca: Option<PathBuf>,
+    /// If set, only the certificate with this SHA-256 fingerprint is trusted, even if it's self-signed
+    pub pin_sha256: Option<[u8; 32]>,
+    /// The name which is sent in SNI and checked in the certificate instead of the host of the address
+    pub server_name: Option<String>,
+    /// PEM files of the certificate chain and the private key which are presented for mutual TLS
+    pub client_identity: Option<(PathBuf, PathBuf)>,
+}
+
+/// Opens TLS connections to the local server and does the websocket handshake over them
+pub(crate) struct TlsClient {
+    connector: TlsConnector,
+    server_name: Option<ServerName<'static>>,
+}
+
+impl TlsClient {
+    /// Loads every file in the options and builds the client
+    pub fn new(options: TlsOptions) -> Result<TlsClient, String> {
+        let provider = Arc::new(ring::default_provider());
+        let builder = ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(|err| err.to_string())?;
+        let builder = match options.pin_sha256 {
+            Some(fingerprint) => builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(PinnedCertificate {
+                    fingerprint,
+                    provider,
+                })),
+            None => builder.with_root_certificates(root_store(options.ca.as_deref())?),
+        };
+        let config = match options.client_identity {
+            Some((certificate, key)) => builder
+                .with_client_auth_cert(load_certificates(&certificate)?, load_key(&key)?)
+                .map_err(|err| format!("invalid client certificate: {err}"))?,
+            None => builder.with_no_client_auth(),
+        };
+        let server_name = match options.server_name {
+            Some(name) => Some(
+                ServerName::try_from(name.clone())
+                    .map_err(|_| format!("invalid TLS server name: {name}"))?,
+            ),
+            None => None,
+        };
+        Ok(TlsClient {
+            connector: TlsConnector::from(Arc::new(config)),
+            server_name,
+        })
+    }
+
+    /// Connects to the host of the request over TLS and opens the websocket
+    pub async fn connect(
+        &self,
+        request: Request,
+    ) -> Result<(WebSocketStream<MaybeTlsStream<TcpStream>>, Response), Error> {
+        let host = request
+            .uri()
+            .host()
+            .ok_or(Error::Url(UrlError::NoHostName))?
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_owned();
+        let port = request.uri().port_u16().unwrap_or(443);
+        let socket = TcpStream::connect((host.as_str(), port)).await?;
+        let server_name = match &self.server_name {
+            Some(server_name) => server_name.clone(),
+            None => ServerName::try_from(host).map_err(|_| Error::Tls(TlsError::InvalidDnsName))?,
+        };
+        let stream = self.connector.connect(server_name, socket).await?;
+        client_async(request, MaybeTlsStream::Rustls(stream)).await
+    }
+}
+
+/// Parses a SHA-256 fingerprint in hex. Colons between the bytes are allowed like what openssl prints.
+pub(crate) fn parse_fingerprint(value: &str) -> Result<[u8; 32], String> {
+    let digits: Vec<u8> = value.bytes().filter(|c| *c != b':').collect();
+    let mut fingerprint = [0u8; 32];
+    if digits.len() != fingerprint.len() * 2 {
+        return Err("SHA-256 fingerprint must be 64 hex digits".to_owned());
+    }
+    for (byte, pair) in fingerprint.iter_mut().zip(digits.chunks(2)) {
+        let pair = std::str::from_utf8(pair).map_err(|_| "invalid hex in fingerprint")?;
+        *byte = u8::from_str_radix(pair, 16).map_err(|_| "invalid hex in fingerprint")?;
+    }
+    Ok(fingerprint)
+}
+
+/// The custom CAs or the public ones if there is none
+fn root_store(ca: Option<&Path>) -> Result<RootCertStore, String> {
+    let mut roots = RootCertStore::empty();
+    match ca {
+        Some(ca) => {
+            for certificate in load_certificates(ca)? {
+                roots
+                    .add(certificate)
+                    .map_err(|err| format!("invalid CA in {}: {err}", ca.display()))?;
+            }
+        }
+        None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+    }
+    Ok(roots)
+}
+
+fn load_certificates(path: &Path) -> Result<Vec<CertificateDer<'static>>, String> {
+    CertificateDer::pem_file_iter(path)
+        .and_then(|certificates| certificates.collect::<Result<Vec<_>, _>>())
+        .map_err(|err| format!("cannot read certificates from {}: {err}", path.display()))
+}
+
+fn load_key(path: &Path) -> Result<PrivateKeyDer<'static>, String> {
+    PrivateKeyDer::from_pem_file(path)
+        .map_err(|err| format!("cannot read private key from {}: {err}", path.display()))
+}
+
+/// Trusts a single certificate by its fingerprint. The handshake signatures are still checked.
+#[derive(Debug)]
+struct PinnedCertificate {
+    fingerprint: [u8; 32],
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedCertificate {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let digest = ::ring::digest::digest(&::ring::digest::SHA256, end_entity.as_ref());
+        match digest.as_ref() == self.fingerprint {
+            true => Ok(ServerCertVerified::assertion()),
+            false => Err(rustls::Error::General(
+                "certificate does not match the pinned fingerprint".to_owned(),
+            )),
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
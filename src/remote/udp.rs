@@ -0,0 +1,62 @@
+use std::net::SocketAddr;
+
+use log::debug;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+
+use crate::admin::Connection;
+
+/// The biggest datagram which we can receive
+const MAX_DATAGRAM_SIZE: usize = 64 * 1024;
+
+/// Proxies the datagrams between a UDP socket which is connected to the forward address
+/// and the pipes of a websocket or a mux stream. Each message in the pipes is a single datagram.
+pub(crate) async fn proxy_udp(
+    udp_socket: UdpSocket,
+    connection: &Connection,
+    socket_sender: mpsc::Sender<Vec<u8>>,
+    mut websocket_receiver: mpsc::Receiver<Vec<u8>>,
+) {
+    let connection_id = connection.id;
+    let mut buffer = vec![0u8; MAX_DATAGRAM_SIZE];
+    loop {
+        tokio::select! {
+            result = udp_socket.recv(&mut buffer) => match result {
+                Ok(n) => {
+                    connection.add_bytes_in(n);
+                    connection.limiter.read(n).await;
+                    if socket_sender.send(buffer[..n].to_owned()).await.is_err() {
+                        break; // websocket closed
+                    }
+                }
+                // Errors such as ICMP port unreachable should not kill the session
+                Err(err) => debug!("UDP socket {connection_id} returned error: {:?}", err),
+            },
+            datagram = websocket_receiver.recv() => match datagram {
+                Some(datagram) => {
+                    connection.limiter.write(datagram.len()).await;
+                    match udp_socket.send(&datagram).await {
+                        Ok(n) => connection.add_bytes_out(n),
+                        Err(err) => debug!("Cannot send datagram of {connection_id}: {:?}", err),
+                    }
+                }
+                None => break, // websocket closed
+            },
+        }
+    }
+    debug!("UDP socket {connection_id} closed");
+}
+
+/// Creates a UDP socket which is connected to the first address
+pub(crate) async fn connect(addresses: &[SocketAddr]) -> std::io::Result<UdpSocket> {
+    let address = *addresses.first().ok_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::NotFound, "address did not resolve")
+    })?;
+    let bind_address: SocketAddr = match address {
+        SocketAddr::V4(_) => "0.0.0.0:0".parse().unwrap(),
+        SocketAddr::V6(_) => "[::]:0".parse().unwrap(),
+    };
+    let udp_socket = UdpSocket::bind(bind_address).await?;
+    udp_socket.connect(address).await?;
+    Ok(udp_socket)
+}
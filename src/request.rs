@@ -0,0 +1,118 @@
+use std::net::SocketAddr;
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// The name of the service which is used when a mapping has no name
+pub(crate) const DEFAULT_SERVICE: &str = "default";
+
+/// A request to open a connection which the local server sends to the remote server.
+/// It's sent as JSON in the control websocket, the idle websockets and the mux open frames.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct ConnectionRequest {
+    /// The id of the connection
+    pub id: Uuid,
+    /// The name of the mapping which the connection belongs to
+    pub service: String,
+    /// What kind of socket the remote server should open
+    #[serde(default)]
+    pub protocol: Protocol,
+    /// If set, the remote server dials this host:port instead of the address of the service
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub destination: Option<String>,
+    /// The address of the client which opened the connection on the local server
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_address: Option<SocketAddr>,
+    /// The address of the listener which the client connected to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub listener_address: Option<SocketAddr>,
+}
+
+/// The transport protocol of a connection
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Protocol {
+    /// A TCP stream
+    #[default]
+    Tcp,
+    /// UDP datagrams where each message in the websocket is a single datagram
+    Udp,
+}
+
+impl ConnectionRequest {
+    /// Converts the request to JSON
+    pub fn encode(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+
+    /// Parses a request from JSON
+    pub fn decode(data: &[u8]) -> serde_json::Result<ConnectionRequest> {
+        serde_json::from_slice(data)
+    }
+}
+
+/// The result of dialing the target of a connection which the remote server reports back.
+/// In websockets it's sent as a JSON text message before any data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum DialStatus {
+    /// The target is connected and data can flow
+    Connected,
+    /// The destination is not allowed by the rules of the remote server
+    Denied,
+    /// The target could not be dialed
+    Failed,
+}
+
+impl DialStatus {
+    /// Converts the status to JSON
+    pub fn encode(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+
+    /// Parses a status from JSON
+    pub fn decode(data: &str) -> serde_json::Result<DialStatus> {
+        serde_json::from_str(data)
+    }
+
+    /// The status as a single byte which is used in mux frames
+    pub fn to_code(self) -> u8 {
+        match self {
+            DialStatus::Connected => 0,
+            DialStatus::Denied => 1,
+            DialStatus::Failed => 2,
+        }
+    }
+
+    /// Parses the byte which is made by to_code
+    pub fn from_code(code: u8) -> Option<DialStatus> {
+        match code {
+            0 => Some(DialStatus::Connected),
+            1 => Some(DialStatus::Denied),
+            2 => Some(DialStatus::Failed),
+            _ => None,
+        }
+    }
+}
+
+/// The result of a connection request which the remote server reports in the control websocket
+/// when it does not open the websocket of the connection at all, like when max_dials is reached.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub(crate) struct DialReport {
+    /// The id of the connection
+    pub id: Uuid,
+    /// Why the connection is not opened
+    pub status: DialStatus,
+}
+
+impl DialReport {
+    /// Converts the report to JSON
+    pub fn encode(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+
+    /// Parses a report from JSON
+    pub fn decode(data: &str) -> serde_json::Result<DialReport> {
+        serde_json::from_str(data)
+    }
+}
@@ -0,0 +1,185 @@
+//! Graceful shutdown on SIGTERM and SIGINT, or Ctrl+C on the platforms which are not unix.
+//!
+//! At first, the listeners stop accepting new connections and the peer is told that we are going away.
+//! Then the open connections can finish until every one of them is done or the drain timeout is reached.
+//! At last, the websockets are closed with a close frame and the program exits.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use log::{info, warn};
+use tokio::sync::{watch, Notify};
+
+/// The text message which tells the peer over the control websocket that we are shutting down
+pub(crate) const SHUTDOWN_MESSAGE: &str = "shutdown";
+
+/// How long the websockets can take to send their close frames
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The phases of the program. They only move forward.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum Phase {
+    /// Everything works as usual
+    Running,
+    /// No new connections are accepted, but the open ones can finish
+    Draining,
+    /// The websockets should be closed right now
+    Closing,
+}
+
+static PHASE: OnceLock<watch::Sender<Phase>> = OnceLock::new();
+
+fn phase_sender() -> &'static watch::Sender<Phase> {
+    PHASE.get_or_init(|| watch::channel(Phase::Running).0)
+}
+
+/// Returns true if the shutdown has started
+pub(crate) fn is_draining() -> bool {
+    *phase_sender().borrow() >= Phase::Draining
+}
+
+/// Resolves once the shutdown has started
+pub(crate) async fn draining() {
+    reached(Phase::Draining).await
+}
+
+/// Resolves once the websockets should be closed
+pub(crate) async fn closing() {
+    reached(Phase::Closing).await
+}
+
+async fn reached(phase: Phase) {
+    let mut receiver = phase_sender().subscribe();
+    // The sender is static, so this never fails
+    let _ = receiver.wait_for(|current| *current >= phase).await;
+}
+
+/// Counts the tasks which we should wait for before exiting
+pub(crate) struct Tracker {
+    count: AtomicUsize,
+    done: Notify,
+}
+
+/// The proxied connections
+pub(crate) static CONNECTIONS: Tracker = Tracker::new();
+/// The websockets which should send a close frame before we exit
+pub(crate) static WEBSOCKETS: Tracker = Tracker::new();
+
+impl Tracker {
+    const fn new() -> Tracker {
+        Tracker {
+            count: AtomicUsize::new(0),
+            done: Notify::const_new(),
+        }
+    }
+
+    /// Counts a task until the returned guard is dropped
+    pub fn track(&'static self) -> TrackerGuard {
+        self.count.fetch_add(1, Ordering::Relaxed);
+        TrackerGuard(self)
+    }
+
+    /// How many tasks are running right now
+    pub fn count(&self) -> usize {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// Waits until every task is finished
+    async fn finished(&self) {
+        loop {
+            let done = self.done.notified();
+            tokio::pin!(done);
+            // Register before checking the count, so we do not miss the last guard
+            done.as_mut().enable();
+            if self.count() == 0 {
+                return;
+            }
+            done.await;
+        }
+    }
+}
+
+/// Keeps a task counted in its tracker
+pub(crate) struct TrackerGuard(&'static Tracker);
+
+impl Drop for TrackerGuard {
+    fn drop(&mut self) {
+        if self.0.count.fetch_sub(1, Ordering::Relaxed) == 1 {
+            self.0.done.notify_waiters();
+        }
+    }
+}
+
+/// The signals which stop the program
+#[cfg(unix)]
+struct Signals {
+    terminate: tokio::signal::unix::Signal,
+    interrupt: tokio::signal::unix::Signal,
+}
+
+#[cfg(unix)]
+impl Signals {
+    fn new() -> Signals {
+        use tokio::signal::unix::{signal, SignalKind};
+        Signals {
+            terminate: signal(SignalKind::terminate()).expect("cannot listen for SIGTERM"),
+            interrupt: signal(SignalKind::interrupt()).expect("cannot listen for SIGINT"),
+        }
+    }
+
+    /// Waits for the next signal and returns its name
+    async fn recv(&mut self) -> &'static str {
+        tokio::select! {
+            _ = self.terminate.recv() => "SIGTERM",
+            _ = self.interrupt.recv() => "SIGINT",
+        }
+    }
+}
+
+// Only Ctrl+C can be caught on the other platforms
+#[cfg(not(unix))]
+struct Signals;
+
+#[cfg(not(unix))]
+impl Signals {
+    fn new() -> Signals {
+        Signals
+    }
+
+    /// Waits for the next Ctrl+C
+    async fn recv(&mut self) -> &'static str {
+        tokio::signal::ctrl_c().await.expect("cannot listen for Ctrl+C");
+        "Ctrl+C"
+    }
+}
+
+/// Waits for SIGTERM or SIGINT (Ctrl+C on other platforms) and then shuts down gracefully.
+/// Returns when the program can exit.
+pub(crate) async fn wait_for_shutdown(drain_timeout: Duration) {
+    let mut signals = Signals::new();
+    let signal = signals.recv().await;
+    info!("Received {signal}, shutting down");
+    phase_sender().send_replace(Phase::Draining);
+    info!(
+        "Waiting up to {}s for {} connections to finish",
+        drain_timeout.as_secs(),
+        CONNECTIONS.count()
+    );
+    // A second signal means that the user does not want to wait
+    tokio::select! {
+        result = tokio::time::timeout(drain_timeout, CONNECTIONS.finished()) => {
+            if result.is_err() {
+                warn!("{} connections did not finish in time", CONNECTIONS.count());
+            }
+        }
+        signal = signals.recv() => warn!("Received {signal} again, closing the connections"),
+    }
+    phase_sender().send_replace(Phase::Closing);
+    if tokio::time::timeout(CLOSE_TIMEOUT, WEBSOCKETS.finished())
+        .await
+        .is_err()
+    {
+        warn!("{} websockets did not close in time", WEBSOCKETS.count());
+    }
+}
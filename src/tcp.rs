@@ -0,0 +1,83 @@
+//! Proxying a TCP socket through the pipes of a websocket or a mux stream.
+//!
+//! An empty packet in the pipes means that its sender is done writing, just like a TCP FIN.
+//! So, each direction is closed on its own and half-closed connections work end to end.
+
+use log::debug;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::{Receiver, Sender};
+
+use crate::admin::Connection;
+use crate::config::tuning;
+
+/// Proxies the data between a TCP socket and the pipes of its connection.
+/// Returns when both directions are finished or as soon as one of them breaks.
+pub(crate) async fn proxy_tcp(
+    socket: TcpStream,
+    connection: &Connection,
+    socket_sender: Sender<Vec<u8>>,
+    mut websocket_receiver: Receiver<Vec<u8>>,
+) {
+    let connection_id = connection.id;
+    let (mut socket_r, mut socket_w) = socket.into_split();
+    // The futures only borrow the pipes, because dropping a pipe closes the whole connection
+    // and we still need the other direction after one side is done.
+    // Read the socket until EOF and then tell the other side. Returns false if the connection broke.
+    let reader = async {
+        let mut buffer = vec![0u8; tuning().read_buffer_size];
+        loop {
+            match socket_r.read(&mut buffer).await {
+                Ok(0) => {
+                    debug!("Socket {connection_id} closed on read");
+                    return socket_sender.send(Vec::new()).await.is_ok();
+                }
+                Ok(n) => {
+                    connection.add_bytes_in(n);
+                    connection.limiter.read(n).await;
+                    if socket_sender.send(buffer[..n].to_owned()).await.is_err() {
+                        return false; // websocket closed
+                    }
+                }
+                Err(err) => {
+                    debug!("Reader socket {connection_id} returned error: {:?}", err);
+                    return false;
+                }
+            }
+        }
+    };
+    // Write in the socket until the other side is done and then shutdown our write half
+    let writer = async {
+        while let Some(data) = websocket_receiver.recv().await {
+            if data.is_empty() {
+                debug!("Socket {connection_id} closed on write");
+                return socket_w.shutdown().await.is_ok();
+            }
+            connection.limiter.write(data.len()).await;
+            if let Err(err) = socket_w.write_all(&data).await {
+                debug!("Writer socket {connection_id} returned error: {:?}", err);
+                return false;
+            }
+            connection.add_bytes_out(data.len());
+        }
+        false // websocket closed
+    };
+    tokio::pin!(reader, writer);
+    let (mut reading, mut writing) = (true, true);
+    while reading || writing {
+        tokio::select! {
+            clean = &mut reader, if reading => {
+                if !clean {
+                    break;
+                }
+                reading = false;
+            }
+            clean = &mut writer, if writing => {
+                if !clean {
+                    break;
+                }
+                writing = false;
+            }
+        }
+    }
+}